@@ -0,0 +1,65 @@
+//! Compression throughput by number of threads.
+//!
+//! Run with `cargo bench --bench compression`; compare the `MB/s` of the
+//! `_1_thread` and `_4_threads` variants for the speedup.
+
+#![feature(test)]
+
+extern crate sneakercopy;
+extern crate test;
+
+use sneakercopy::flate::{self, Codec};
+use sneakercopy::tarbox::attributes::Compression;
+use std::io::Write;
+use test::Bencher;
+
+/// Four blocks of log-like text, so that every thread has work to do.
+fn make_input() -> Vec<u8> {
+    let words = [
+        "GET", "POST", "/api/v3/", "messages", "200", "404", "sneakercopy", "tarbox", "secret",
+        "2018-08-11T12:00:00Z", "\n",
+    ];
+
+    let mut state: u32 = 0x5eed;
+    let mut data = Vec::with_capacity(4 * flate::BLOCK_SIZE);
+    while data.len() < 4 * flate::BLOCK_SIZE {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        data.extend_from_slice(words[(state >> 16) as usize % words.len()].as_bytes());
+        data.push(b' ');
+    }
+
+    data
+}
+
+fn bench_compress(b: &mut Bencher, compression: Compression, threads: usize) {
+    let data = make_input();
+    let mut codec = Codec::new(compression, None).unwrap();
+    codec.set_threads(threads);
+
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let mut compressed = flate::sampling_compressor(&codec, |_: &Codec| Ok(Vec::new()));
+        compressed.write_all(&data).unwrap();
+        flate::finish_sampler(compressed).unwrap()
+    });
+}
+
+#[bench]
+fn gzip_1_thread(b: &mut Bencher) {
+    bench_compress(b, Compression::Gzip, 1);
+}
+
+#[bench]
+fn gzip_4_threads(b: &mut Bencher) {
+    bench_compress(b, Compression::Gzip, 4);
+}
+
+#[bench]
+fn zstd_1_thread(b: &mut Bencher) {
+    bench_compress(b, Compression::Zstd, 1);
+}
+
+#[bench]
+fn zstd_4_threads(b: &mut Bencher) {
+    bench_compress(b, Compression::Zstd, 4);
+}
@@ -9,9 +9,21 @@ extern crate sodiumoxide;
 extern crate structopt;
 
 use quicli::prelude::*;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read};
 use std::path::PathBuf;
 
-use sneakercopy::{errors::*, tarbox, *};
+use sneakercopy::{
+    errors::*,
+    tarbox,
+    tarbox::attributes::Compression,
+    tarbox::keyslot::KeySlot,
+    tarbox::recipient::Identity,
+    tarbox::secret::{Kdf, KdfAlgorithm, KdfCost, Keyfile},
+    *,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
@@ -27,8 +39,12 @@ struct Cli {
 enum Subcommand {
     #[structopt(name = "seal", about = "Seal an encrypted archive")]
     Seal {
-        #[structopt(help = "File/folder path to archive", parse(from_os_str))]
-        path: PathBuf,
+        #[structopt(
+            help = "File/folder paths to archive, or `-` to read a file from stdin",
+            parse(from_os_str),
+            raw(required = "true")
+        )]
+        paths: Vec<PathBuf>,
 
         #[structopt(
             short = "o",
@@ -38,22 +54,170 @@ enum Subcommand {
         )]
         output: Option<PathBuf>,
 
+        #[structopt(
+            long = "stdout",
+            help = "Write the tarbox to stdout; the secret goes to stderr",
+            raw(conflicts_with = r#""output""#)
+        )]
+        stdout: bool,
+
+        #[structopt(
+            long = "name",
+            help = "Archive name of the file read from stdin"
+        )]
+        name: Option<String>,
+
         #[structopt(
             short = "f",
             long = "force",
             help = "Force overwriting of output"
         )]
         force: bool,
+
+        #[structopt(
+            long = "kdf",
+            help = "Key derivation function",
+            default_value = "scrypt",
+            raw(possible_values = r#"&["scrypt", "argon2id"]"#)
+        )]
+        kdf: KdfAlgorithm,
+
+        #[structopt(
+            long = "kdf-cost",
+            help = "Key derivation cost preset",
+            default_value = "interactive",
+            raw(possible_values = r#"&["interactive", "moderate", "sensitive"]"#)
+        )]
+        kdf_cost: KdfCost,
+
+        #[structopt(
+            long = "secrets",
+            help = "Number of secrets to generate, each able to open the tarbox [default: 1, or 0 with --recipient or --shares]"
+        )]
+        secrets: Option<usize>,
+
+        #[structopt(
+            long = "shares",
+            help = "Split a further secret into this many shares",
+            raw(requires = r#""threshold""#)
+        )]
+        shares: Option<usize>,
+
+        #[structopt(
+            long = "threshold",
+            help = "Number of shares needed to open the tarbox",
+            raw(requires = r#""shares""#)
+        )]
+        threshold: Option<usize>,
+
+        #[structopt(
+            short = "r",
+            long = "recipient",
+            help = "Also seal for this public key; repeat for more recipients",
+            raw(number_of_values = "1")
+        )]
+        recipients: Vec<String>,
+
+        #[structopt(
+            short = "k",
+            long = "keyfile",
+            help = "Keyfile needed along with each secret; with --secrets 0 it opens the tarbox alone",
+            parse(from_os_str)
+        )]
+        keyfile: Option<PathBuf>,
+
+        #[structopt(
+            long = "compression",
+            help = "Compression algorithm",
+            default_value = "gzip",
+            raw(possible_values = r#"&["gzip", "zstd", "xz", "none"]"#)
+        )]
+        compression: Compression,
+
+        #[structopt(
+            long = "level",
+            help = "Compression level: 0-9 for gzip and xz, 1-22 for zstd"
+        )]
+        level: Option<u32>,
+
+        #[structopt(
+            long = "always-compress",
+            help = "Compress even data that does not shrink, like archives or media"
+        )]
+        always_compress: bool,
+
+        #[structopt(
+            long = "threads",
+            help = "Number of threads to compress on",
+            default_value = "1"
+        )]
+        threads: usize,
+
+        #[structopt(
+            long = "exclude",
+            help = "Skip files and directories matching this glob",
+            raw(number_of_values = "1")
+        )]
+        exclude: Vec<String>,
+
+        #[structopt(
+            long = "exclude-from",
+            help = "Read exclude globs from a file, one per line",
+            parse(from_os_str),
+            raw(number_of_values = "1")
+        )]
+        exclude_from: Vec<PathBuf>,
+
+        #[structopt(
+            long = "include",
+            help = "Only pack files matching this glob",
+            raw(number_of_values = "1")
+        )]
+        include: Vec<String>,
+
+        #[structopt(
+            long = "respect-gitignore",
+            help = "Skip files ignored by .gitignore files"
+        )]
+        respect_gitignore: bool,
     },
 
     #[structopt(name = "unseal", about = "Unseal an encrypted archive")]
     Unseal {
-        #[structopt(help = "Path to encrypted archive", parse(from_os_str))]
+        #[structopt(
+            help = "Path to encrypted archive, or `-` to read it from stdin",
+            parse(from_os_str)
+        )]
         path: PathBuf,
 
         #[structopt(help = "Password used for encryption")]
         password: Option<String>,
 
+        #[structopt(
+            short = "i",
+            long = "identity",
+            help = "Identity file of a recipient, used instead of a secret",
+            parse(from_os_str),
+            raw(conflicts_with = r#""password""#)
+        )]
+        identity: Option<PathBuf>,
+
+        #[structopt(
+            short = "k",
+            long = "keyfile",
+            help = "Keyfile the tarbox was sealed with",
+            parse(from_os_str),
+            raw(conflicts_with = r#""identity""#)
+        )]
+        keyfile: Option<PathBuf>,
+
+        #[structopt(
+            long = "share",
+            help = "Share of the secret, used instead of it; repeat until enough are given",
+            raw(number_of_values = "1", conflicts_with_all = r#"&["password", "identity"]"#)
+        )]
+        shares: Vec<String>,
+
         #[structopt(
             short = "C",
             long = "extract-to",
@@ -61,9 +225,217 @@ enum Subcommand {
             parse(from_os_str)
         )]
         dest: Option<PathBuf>,
+
+        #[structopt(
+            long = "stdout",
+            help = "Write the contents of a single-file tarbox to stdout",
+            raw(conflicts_with = r#""dest""#)
+        )]
+        stdout: bool,
+
+        #[structopt(
+            long = "skip-devices",
+            help = "Skip device nodes instead of refusing the archive"
+        )]
+        skip_devices: bool,
+
+        #[structopt(
+            long = "on-conflict",
+            help = "What to do when a file already exists",
+            default_value = "error",
+            raw(possible_values = r#"&["error", "skip", "overwrite", "rename", "newer"]"#)
+        )]
+        on_conflict: pack::ConflictPolicy,
+
+        #[structopt(
+            short = "m",
+            long = "member",
+            help = "Only extract this path or glob from the archive",
+            raw(number_of_values = "1")
+        )]
+        members: Vec<String>,
+
+        #[structopt(
+            long = "strip-components",
+            help = "Remove this many leading path components on extraction",
+            default_value = "0"
+        )]
+        strip_components: usize,
+    },
+
+    #[structopt(name = "list", about = "List the contents of an encrypted archive")]
+    List {
+        #[structopt(help = "Path to encrypted archive", parse(from_os_str))]
+        path: PathBuf,
+
+        #[structopt(help = "Password used for encryption")]
+        password: Option<String>,
+
+        #[structopt(
+            short = "i",
+            long = "identity",
+            help = "Identity file of a recipient, used instead of a secret",
+            parse(from_os_str),
+            raw(conflicts_with = r#""password""#)
+        )]
+        identity: Option<PathBuf>,
+
+        #[structopt(
+            short = "k",
+            long = "keyfile",
+            help = "Keyfile the tarbox was sealed with",
+            parse(from_os_str),
+            raw(conflicts_with = r#""identity""#)
+        )]
+        keyfile: Option<PathBuf>,
+
+        #[structopt(
+            long = "share",
+            help = "Share of the secret, used instead of it; repeat until enough are given",
+            raw(number_of_values = "1", conflicts_with_all = r#"&["password", "identity"]"#)
+        )]
+        shares: Vec<String>,
+
+        #[structopt(
+            short = "l",
+            long = "long",
+            help = "Show permissions, owner and date like `tar -tv`"
+        )]
+        long: bool,
+    },
+
+    #[structopt(
+        name = "cat",
+        about = "Write a single file from an encrypted archive to stdout"
+    )]
+    Cat {
+        #[structopt(help = "Path to encrypted archive", parse(from_os_str))]
+        path: PathBuf,
+
+        #[structopt(help = "Path of the file inside the archive", parse(from_os_str))]
+        member: PathBuf,
+
+        #[structopt(help = "Password used for encryption")]
+        password: Option<String>,
+
+        #[structopt(
+            short = "i",
+            long = "identity",
+            help = "Identity file of a recipient, used instead of a secret",
+            parse(from_os_str),
+            raw(conflicts_with = r#""password""#)
+        )]
+        identity: Option<PathBuf>,
+
+        #[structopt(
+            short = "k",
+            long = "keyfile",
+            help = "Keyfile the tarbox was sealed with",
+            parse(from_os_str),
+            raw(conflicts_with = r#""identity""#)
+        )]
+        keyfile: Option<PathBuf>,
+
+        #[structopt(
+            long = "share",
+            help = "Share of the secret, used instead of it; repeat until enough are given",
+            raw(number_of_values = "1", conflicts_with_all = r#"&["password", "identity"]"#)
+        )]
+        shares: Vec<String>,
+    },
+
+    #[structopt(
+        name = "keygen",
+        about = "Create an identity file for receiving tarboxes and print its public key"
+    )]
+    Keygen {
+        #[structopt(help = "Path of the new identity file", parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    #[structopt(
+        name = "rekey",
+        about = "Replace a secret of an encrypted archive without re-encrypting it"
+    )]
+    Rekey {
+        #[structopt(help = "Path to encrypted archive", parse(from_os_str))]
+        path: PathBuf,
+
+        #[structopt(help = "Secret to replace")]
+        password: Option<String>,
+
+        #[structopt(
+            short = "k",
+            long = "keyfile",
+            help = "Keyfile the tarbox was sealed with",
+            parse(from_os_str)
+        )]
+        keyfile: Option<PathBuf>,
+
+        #[structopt(
+            long = "share",
+            help = "Share of the secret to replace, used instead of it; the new secret replaces all the shares",
+            raw(number_of_values = "1", conflicts_with = r#""password""#)
+        )]
+        shares: Vec<String>,
+    },
+
+    #[structopt(
+        name = "inspect",
+        about = "Show the header of an encrypted archive without decrypting it"
+    )]
+    Inspect {
+        #[structopt(help = "Path to encrypted archive", parse(from_os_str))]
+        path: PathBuf,
+
+        #[structopt(long = "json", help = "Print the header as JSON")]
+        json: bool,
+    },
+
+    #[structopt(
+        name = "verify",
+        about = "Check the secret and integrity of an encrypted archive without extracting it"
+    )]
+    Verify {
+        #[structopt(help = "Path to encrypted archive", parse(from_os_str))]
+        path: PathBuf,
+
+        #[structopt(help = "Password used for encryption")]
+        password: Option<String>,
+
+        #[structopt(
+            short = "i",
+            long = "identity",
+            help = "Identity file of a recipient, used instead of a secret",
+            parse(from_os_str),
+            raw(conflicts_with = r#""password""#)
+        )]
+        identity: Option<PathBuf>,
+
+        #[structopt(
+            short = "k",
+            long = "keyfile",
+            help = "Keyfile the tarbox was sealed with",
+            parse(from_os_str),
+            raw(conflicts_with = r#""identity""#)
+        )]
+        keyfile: Option<PathBuf>,
+
+        #[structopt(
+            long = "share",
+            help = "Share of the secret, used instead of it; repeat until enough are given",
+            raw(number_of_values = "1", conflicts_with_all = r#"&["password", "identity"]"#)
+        )]
+        shares: Vec<String>,
     },
 }
 
+/// Exit codes that let scripts tell apart why a tarbox could not be opened.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_WRONG_SECRET: i32 = 2;
+const EXIT_CORRUPT_HEADER: i32 = 3;
+const EXIT_CORRUPT_ARCHIVE: i32 = 4;
+
 main!(|args: Cli, log_level: verbosity| {
     sodiumoxide::init().expect("could not init sodiumoxide lib");
 
@@ -77,7 +449,7 @@ main!(|args: Cli, log_level: verbosity| {
             println!("backtrace: {:?}", backtrace);
         }
 
-        std::process::exit(1);
+        std::process::exit(exit_code(e));
     }
 });
 
@@ -85,20 +457,174 @@ fn entrypoint(args: Cli) -> sneakercopy::errors::Result<()> {
     let action = &args.subcmd;
     match action {
         Subcommand::Seal {
-            path,
+            paths,
             output,
+            stdout,
+            name,
             force,
-        } => seal_subcmd(&args, &path.canonicalize().unwrap(), output, force)?,
+            kdf,
+            kdf_cost,
+            secrets,
+            shares,
+            threshold,
+            recipients,
+            keyfile,
+            compression,
+            level,
+            always_compress,
+            threads,
+            exclude,
+            exclude_from,
+            include,
+            respect_gitignore,
+        } => {
+            let mut codec = flate::Codec::new(*compression, *level)?;
+            codec.set_skip_incompressible(!*always_compress);
+            codec.set_threads(*threads);
+
+            let mut public_keys = Vec::with_capacity(recipients.len());
+            for recipient in recipients {
+                public_keys.push(recipient.parse()?);
+            }
+
+            let default_secrets = if public_keys.is_empty() && shares.is_none() {
+                1
+            } else {
+                0
+            };
+            let seal_opts = SealOptions {
+                kdf: Kdf::preset(*kdf, *kdf_cost)?,
+                codec: codec,
+                secrets: secrets.unwrap_or(default_secrets),
+                recipients: public_keys,
+                keyfile: read_keyfile(keyfile)?,
+                shares: shares.unwrap_or(0),
+                threshold: threshold.unwrap_or(0),
+            };
+
+            let mut exclude = exclude.clone();
+            for path in exclude_from {
+                exclude.extend(filter::read_pattern_file(path)?);
+            }
+
+            let opts = pack::PackOptions {
+                filter: filter::PathFilter::new(&exclude, include)?,
+                respect_gitignore: *respect_gitignore,
+            };
+
+            if paths.iter().any(|p| is_stdio(p)) {
+                if paths.len() > 1 {
+                    return Err(ErrorKind::StdinWithPaths.into());
+                }
+                seal_stdin_subcmd(&args, output, stdout, name, force, &seal_opts)?
+            } else {
+                seal_subcmd(&args, paths, output, stdout, force, &seal_opts, &opts)?
+            }
+        }
         Subcommand::Unseal {
             path,
             password,
+            identity,
+            keyfile,
+            shares,
             dest,
-        } => unseal_subcmd(&args, &path.canonicalize().unwrap(), dest, password)?,
+            stdout,
+            skip_devices,
+            on_conflict,
+            members,
+            strip_components,
+        } => {
+            let filter = if members.is_empty() {
+                None
+            } else {
+                Some(filter::members(members)?)
+            };
+
+            let opts = pack::UnpackOptions {
+                skip_devices: *skip_devices,
+                on_conflict: *on_conflict,
+                filter: filter,
+                strip_components: *strip_components,
+            };
+
+            let use_tty = is_stdio(path) || *stdout;
+            let unlock = unlock(password, keyfile, identity, shares, use_tty)?;
+            unseal_subcmd(&args, path, dest, stdout, unlock, &opts)?
+        }
+        Subcommand::List {
+            path,
+            password,
+            identity,
+            keyfile,
+            shares,
+            long,
+        } => {
+            let unlock = unlock(password, keyfile, identity, shares, false)?;
+            list_subcmd(&args, &path.canonicalize().unwrap(), unlock, long)?
+        }
+        Subcommand::Cat {
+            path,
+            member,
+            password,
+            identity,
+            keyfile,
+            shares,
+        } => {
+            let unlock = unlock(password, keyfile, identity, shares, true)?;
+            cat_subcmd(&args, &path.canonicalize().unwrap(), member, unlock)?
+        }
+        Subcommand::Verify {
+            path,
+            password,
+            identity,
+            keyfile,
+            shares,
+        } => {
+            let unlock = unlock(password, keyfile, identity, shares, false)?;
+            verify_subcmd(&args, &path.canonicalize().unwrap(), unlock)?
+        }
+        Subcommand::Keygen { output } => keygen_subcmd(&args, output)?,
+        Subcommand::Rekey {
+            path,
+            password,
+            keyfile,
+            shares,
+        } => {
+            let unlock = secret_unlock(password, keyfile, shares, false)?;
+            rekey_subcmd(&args, &path.canonicalize().unwrap(), unlock, !shares.is_empty())?
+        }
+        Subcommand::Inspect { path, json } => {
+            inspect_subcmd(&args, &path.canonicalize().unwrap(), json)?
+        }
     }
 
     Ok(())
 }
 
+fn exit_code(e: &sneakercopy::errors::Error) -> i32 {
+    match e.kind() {
+        // A `secretbox` cannot tell a wrong key from a damaged box, but the
+        // key-check chunk of a `secretstream` tarbox can.
+        ErrorKind::SecretBoxOpenFail
+        | ErrorKind::SecretStreamOpenFail
+        | ErrorKind::WrongSecret
+        | ErrorKind::InvalidShare(_)
+        | ErrorKind::DuplicateShare(_)
+        | ErrorKind::NotEnoughShares(..)
+        | ErrorKind::MismatchedShares(_)
+        | ErrorKind::InconsistentShares
+        | ErrorKind::MistypedSecret
+        | ErrorKind::MistypedWord(..)
+        | ErrorKind::NotARecipient => EXIT_WRONG_SECRET,
+        ErrorKind::Tarbox(tarbox::errors::ErrorKind::EmptyKeyfile(_)) => EXIT_FAILURE,
+        ErrorKind::HeaderAuthFail | ErrorKind::Tarbox(_) => EXIT_CORRUPT_HEADER,
+        ErrorKind::CorruptArchive
+        | ErrorKind::SecretStreamTruncated
+        | ErrorKind::SecretStreamTrailingData => EXIT_CORRUPT_ARCHIVE,
+        _ => EXIT_FAILURE,
+    }
+}
+
 fn check_path(path: &PathBuf) -> sneakercopy::errors::Result<()> {
     debug!("checking path existence: {:?}", path);
     if !path.exists() {
@@ -110,16 +636,177 @@ fn check_path(path: &PathBuf) -> sneakercopy::errors::Result<()> {
     Ok(())
 }
 
+/// Whether `path` stands for stdin or stdout.
+fn is_stdio(path: &PathBuf) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn read_keyfile(keyfile: &Option<PathBuf>) -> sneakercopy::errors::Result<Option<Keyfile>> {
+    match *keyfile {
+        Some(ref path) => {
+            check_path(path)?;
+            Ok(Some(Keyfile::read_from(path)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Opens tarboxes with `password`, or the secret recovered from `shares`,
+/// and the keyfile at `keyfile`. A missing password is prompted for once
+/// the tarbox turns out to need one. When stdin or stdout carry tarbox data
+/// the prompt goes through the terminal instead.
+fn secret_unlock(
+    password: &Option<String>,
+    keyfile: &Option<PathBuf>,
+    shares: &[String],
+    use_tty: bool,
+) -> sneakercopy::errors::Result<SecretUnlock> {
+    let prompt = move || {
+        let password = if use_tty {
+            rpassword::read_password_from_tty(Some("secret: "))
+        } else {
+            rpassword::prompt_password_stdout("secret: ")
+        };
+
+        password.expect("can't open tarbox without a secret!")
+    };
+
+    let password = if shares.is_empty() {
+        password.clone()
+    } else {
+        Some(shamir::recover(shares)?)
+    };
+
+    Ok(SecretUnlock {
+        password: password,
+        keyfile: read_keyfile(keyfile)?,
+        prompt: Some(Box::new(prompt)),
+    })
+}
+
+/// Opens tarboxes with the identity file at `identity` if one is given,
+/// otherwise as `secret_unlock` does.
+fn unlock(
+    password: &Option<String>,
+    keyfile: &Option<PathBuf>,
+    identity: &Option<PathBuf>,
+    shares: &[String],
+    use_tty: bool,
+) -> sneakercopy::errors::Result<Unlock> {
+    match *identity {
+        Some(ref path) => Ok(Unlock::Identity(Identity::read_from(path)?)),
+        None => Ok(Unlock::Secret(secret_unlock(password, keyfile, shares, use_tty)?)),
+    }
+}
+
+fn print_sealed(sealed: &SealedSecrets, opts: &SealOptions, stdout: bool) {
+    let mut lines: Vec<String> = sealed
+        .secrets
+        .iter()
+        .map(|secret| format!("secret: {}", secret.password()))
+        .collect();
+    for share in &sealed.shares {
+        lines.push(format!(
+            "share {}/{}: {}",
+            share.index(),
+            sealed.shares.len(),
+            share
+        ));
+    }
+    if let Some(share) = sealed.shares.first() {
+        lines.push(format!(
+            "any {} of the {} shares open the tarbox",
+            share.threshold(),
+            sealed.shares.len()
+        ));
+    }
+    match (sealed.secrets.is_empty() && sealed.shares.is_empty(), opts.keyfile.is_some()) {
+        (false, true) => lines.push("each secret also needs the keyfile".to_string()),
+        (true, true) => lines.push("sealed for the keyfile".to_string()),
+        _ => (),
+    }
+    if !opts.recipients.is_empty() {
+        lines.push(format!("sealed for {} recipient(s)", opts.recipients.len()));
+    }
+
+    if stdout {
+        eprintln!("{}", lines.join("\n"));
+    } else {
+        println!("\n{}", lines.join("\n"));
+    }
+}
+
 fn seal_subcmd(
     _args: &Cli,
-    path: &PathBuf,
+    paths: &[PathBuf],
     output: &Option<PathBuf>,
+    stdout: &bool,
     force: &bool,
+    seal_opts: &SealOptions,
+    opts: &pack::PackOptions,
 ) -> sneakercopy::errors::Result<()> {
-    check_path(&path)?;
+    let mut canonical = Vec::with_capacity(paths.len());
+    for path in paths {
+        check_path(&path)?;
+        canonical.push(path.canonicalize()?);
+    }
 
-    let secret = seal_path(&path, &output, *force)?;
-    println!("\nsecret: {}", secret.password());
+    if !*stdout {
+        let sealed = seal_path(&canonical, &output, *force, seal_opts, opts)?;
+        print_sealed(&sealed, seal_opts, false);
+        return Ok(());
+    }
+
+    let names = pack::entry_names(&canonical)?;
+    let original_name = match names.len() {
+        1 => Some(names[0].to_string_lossy().into_owned()),
+        _ => None,
+    };
+
+    let stdout = io::stdout();
+    let (sealed, _) = seal_archive(stdout.lock(), seal_opts, original_name, false, |dest| {
+        pack::pack_archive(&canonical, dest, opts)?;
+        Ok(())
+    })?;
+    print_sealed(&sealed, seal_opts, true);
+
+    Ok(())
+}
+
+fn seal_stdin_subcmd(
+    _args: &Cli,
+    output: &Option<PathBuf>,
+    stdout: &bool,
+    name: &Option<String>,
+    force: &bool,
+    seal_opts: &SealOptions,
+) -> sneakercopy::errors::Result<()> {
+    // Without `--name`, `-o db.tarbox` stores the data as `db`.
+    let name = name.clone().unwrap_or_else(|| {
+        output
+            .as_ref()
+            .filter(|o| !o.is_dir())
+            .and_then(|o| o.file_name())
+            .map(|n| n.to_string_lossy().trim_end_matches(".tarbox").to_string())
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| "stdin".to_string())
+    });
+
+    let pack_stdin = |dest: &mut dyn io::Write| {
+        let stdin = io::stdin();
+        pack::pack_reader(&name, stdin.lock(), dest)?;
+        Ok(())
+    };
+
+    let sealed = if *stdout {
+        let stdout = io::stdout();
+        seal_archive(stdout.lock(), seal_opts, Some(name.clone()), false, pack_stdin)?.0
+    } else {
+        let target_path = output_path(&[PathBuf::from(&name)], output)?;
+        let target_file = BufWriter::new(create_output(&target_path, *force)?);
+        seal_archive(target_file, seal_opts, Some(name.clone()), true, pack_stdin)?.0
+    };
+    print_sealed(&sealed, seal_opts, *stdout);
 
     Ok(())
 }
@@ -128,20 +815,319 @@ fn unseal_subcmd(
     _args: &Cli,
     path: &PathBuf,
     dest: &Option<PathBuf>,
-    password: &Option<String>,
+    stdout: &bool,
+    unlock: Unlock,
+    opts: &pack::UnpackOptions,
+) -> sneakercopy::errors::Result<()> {
+    let (source, default_dest): (Box<dyn Read>, PathBuf) = if is_stdio(path) {
+        (Box::new(io::stdin()), env::current_dir()?)
+    } else {
+        check_path(&path)?;
+        let path = path.canonicalize()?;
+        (
+            Box::new(File::open(&path)?),
+            path.parent().unwrap().to_path_buf(),
+        )
+    };
+
+    if *stdout {
+        let stdout = io::stdout();
+        unseal_to_writer(source, &mut stdout.lock(), unlock)?;
+        return Ok(());
+    }
+
+    let dest = dest.clone().unwrap_or(default_dest);
+    let summary = unseal_reader(source, &dest, unlock, opts)?;
+
+    if summary != pack::UnpackSummary::default() {
+        println!();
+    }
+    for path in summary.skipped.iter() {
+        println!("skipped: {}", path.display());
+    }
+    for path in summary.overwritten.iter() {
+        println!("overwritten: {}", path.display());
+    }
+    for (path, renamed) in summary.renamed.iter() {
+        println!("renamed: {} -> {}", path.display(), renamed.display());
+    }
+
+    Ok(())
+}
+
+fn list_subcmd(
+    _args: &Cli,
+    path: &PathBuf,
+    unlock: Unlock,
+    long: &bool,
 ) -> sneakercopy::errors::Result<()> {
     check_path(&path)?;
 
-    let password = password.clone().unwrap_or_else(|| {
-        return rpassword::prompt_password_stdout("secret: ")
-            .expect("can't open tarbox without a secret!");
-    });
+    let entries = list_path(&path, unlock)?;
+
+    println!();
+    for entry in entries.iter() {
+        if *long {
+            let mut name = entry.path.display().to_string();
+            if let Some(ref link) = entry.link_name {
+                let arrow = if entry.type_name() == "hardlink" {
+                    "link to"
+                } else {
+                    "->"
+                };
+                name = format!("{} {} {}", name, arrow, link.display());
+            }
+
+            println!(
+                "{} {} {:>10} {} {}",
+                entry.permissions(),
+                entry.owner,
+                entry.size,
+                entry.formatted_mtime(),
+                name
+            );
+        } else {
+            println!(
+                "{}\t{}\t{:o}\t{}\t{}",
+                entry.path.display(),
+                entry.size,
+                entry.mode,
+                entry.mtime,
+                entry.type_name()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn cat_subcmd(
+    _args: &Cli,
+    path: &PathBuf,
+    member: &PathBuf,
+    unlock: Unlock,
+) -> sneakercopy::errors::Result<()> {
+    check_path(&path)?;
+
+    let stdout = io::stdout();
+    cat_path(&path, &member, &mut stdout.lock(), unlock)?;
+
+    Ok(())
+}
+
+fn verify_subcmd(
+    _args: &Cli,
+    path: &PathBuf,
+    unlock: Unlock,
+) -> sneakercopy::errors::Result<()> {
+    check_path(&path)?;
+
+    let stats = verify_path(&path, unlock)?;
+    println!("\nok: {} entries, {} bytes", stats.entries, stats.bytes);
+
+    Ok(())
+}
+
+fn keygen_subcmd(_args: &Cli, output: &PathBuf) -> sneakercopy::errors::Result<()> {
+    let identity = Identity::generate();
+    identity.write_to(&output)?;
+    println!("public key: {}", identity.public_key());
 
-    let sb = tarbox::TarboxSecretBuilder::new();
-    let sb = sb.password(password);
-    let dest = dest.clone().unwrap_or(path.parent().unwrap().to_path_buf());
+    Ok(())
+}
+
+fn rekey_subcmd(
+    _args: &Cli,
+    path: &PathBuf,
+    unlock: SecretUnlock,
+    shared: bool,
+) -> sneakercopy::errors::Result<()> {
+    check_path(&path)?;
+
+    let secret = rekey_path(&path, unlock)?;
+    println!("\nsecret: {}", secret.password());
+    if shared {
+        println!("the shares no longer open the tarbox; the secret replaces them");
+    }
+
+    Ok(())
+}
+
+fn inspect_subcmd(_args: &Cli, path: &PathBuf, json: &bool) -> sneakercopy::errors::Result<()> {
+    check_path(&path)?;
+
+    let info = inspect_path(&path)?;
+    let attrs = &info.attributes;
+    // The KDF is only used directly by tarboxes without key slots
+    let kdf = attrs.salt().map(|_| attrs.kdf());
+
+    if *json {
+        let null = || "null".to_string();
+        let key_slots: Vec<String> = attrs
+            .key_slots()
+            .iter()
+            .map(|slot| match *slot {
+                KeySlot::Secret { ref kdf, .. } => format!(
+                    "{{\"type\": {}, \"kdf\": {}, \"kdf_opslimit\": {}, \"kdf_memlimit\": {}}}",
+                    json_string(slot.name()),
+                    json_string(kdf.algorithm().name()),
+                    kdf.opslimit(),
+                    kdf.memlimit()
+                ),
+                KeySlot::Recipient { .. } => format!("{{\"type\": {}}}", json_string(slot.name())),
+            })
+            .collect();
+        let fields = [
+            ("path", json_string(&path.display().to_string())),
+            (
+                "magic",
+                json_string(&format!("{:02x}{:02x}", info.magic[0], info.magic[1])),
+            ),
+            ("version", info.version.to_string()),
+            ("cipher", json_string(attrs.cipher().name())),
+            ("compression", json_string(attrs.compression().name())),
+            (
+                "compression_blocks",
+                attrs
+                    .compression_blocks()
+                    .map(|b| b.to_string())
+                    .unwrap_or_else(null),
+            ),
+            ("key_slots", format!("[{}]", key_slots.join(", "))),
+            (
+                "kdf",
+                kdf.map(|k| json_string(k.algorithm().name()))
+                    .unwrap_or_else(null),
+            ),
+            (
+                "kdf_opslimit",
+                kdf.map(|k| k.opslimit().to_string()).unwrap_or_else(null),
+            ),
+            (
+                "kdf_memlimit",
+                kdf.map(|k| k.memlimit().to_string()).unwrap_or_else(null),
+            ),
+            (
+                "salt",
+                attrs
+                    .encoded_salt()
+                    .map(|s| json_string(&s))
+                    .unwrap_or_else(null),
+            ),
+            (
+                "nonce",
+                attrs.encoded_nonce().map(|n| json_string(&n)).unwrap_or_else(null),
+            ),
+            (
+                "created",
+                attrs.created().map(|c| c.to_string()).unwrap_or_else(null),
+            ),
+            (
+                "original_name",
+                attrs.original_name().map(|n| json_string(n)).unwrap_or_else(null),
+            ),
+            ("header_length", info.header_len.to_string()),
+            ("payload_length", info.payload_len.to_string()),
+        ];
+
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| format!("  {}: {}", json_string(key), value))
+            .collect();
+        println!("{{\n{}\n}}", fields.join(",\n"));
+
+        return Ok(());
+    }
 
-    unseal_path(&path, &dest, sb)?;
+    println!("magic:          {:02x}{:02x}", info.magic[0], info.magic[1]);
+    println!("version:        {}", info.version);
+    println!("cipher:         {}", attrs.cipher().name());
+    match attrs.compression_blocks() {
+        Some(block_size) => println!(
+            "compression:    {} ({} byte blocks)",
+            attrs.compression().name(),
+            block_size
+        ),
+        None => println!("compression:    {}", attrs.compression().name()),
+    }
+    for (i, slot) in attrs.key_slots().iter().enumerate() {
+        match *slot {
+            KeySlot::Secret { ref kdf, .. } => println!(
+                "key slot {}:     {}, {} (opslimit {}, memlimit {})",
+                i,
+                slot.name(),
+                kdf.algorithm().name(),
+                kdf.opslimit(),
+                kdf.memlimit()
+            ),
+            KeySlot::Recipient { .. } => println!("key slot {}:     {}", i, slot.name()),
+        }
+    }
+    if let Some(kdf) = kdf {
+        println!(
+            "kdf:            {} (opslimit {}, memlimit {})",
+            kdf.algorithm().name(),
+            kdf.opslimit(),
+            kdf.memlimit()
+        );
+    }
+    if let Some(salt) = attrs.encoded_salt() {
+        println!("salt:           {}", salt);
+    }
+    if let Some(nonce) = attrs.encoded_nonce() {
+        println!("nonce:          {}", nonce);
+    }
+    if let Some(created) = attrs.created() {
+        println!(
+            "created:        {} UTC",
+            sneakercopy::pack::format_timestamp(created)
+        );
+    }
+    if let Some(name) = attrs.original_name() {
+        println!("original name:  {}", name);
+    }
+    println!("header length:  {} bytes", info.header_len);
+    println!("payload length: {} bytes", info.payload_len);
 
     Ok(())
 }
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code_for(kind: ErrorKind) -> i32 {
+        exit_code(&kind.into())
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(code_for(ErrorKind::SecretBoxOpenFail), EXIT_WRONG_SECRET);
+        assert_eq!(code_for(ErrorKind::SecretStreamOpenFail), EXIT_WRONG_SECRET);
+        assert_eq!(code_for(ErrorKind::HeaderAuthFail), EXIT_CORRUPT_HEADER);
+        assert_eq!(code_for(ErrorKind::CorruptArchive), EXIT_CORRUPT_ARCHIVE);
+        assert_eq!(code_for(ErrorKind::SecretStreamTruncated), EXIT_CORRUPT_ARCHIVE);
+        assert_eq!(
+            code_for(ErrorKind::PathDoesNotExist("nope".into())),
+            EXIT_FAILURE
+        );
+    }
+}
@@ -1,16 +1,471 @@
+//! Encryption stages of the tarbox pipeline.
+//!
+//! New tarboxes are encrypted with libsodium's `secretstream`: the payload is
+//! split into `CHUNK_SIZE` chunks, each sealed with its own authentication
+//! tag, and the last chunk is tagged `Final`. The stream header comes first.
+//!
+//! ```text
+//! +------------+-----------+------------+----------------+-----+-------------------+
+//! | STREAM HDR | KEY CHECK | HEADER TAG |    CHUNK 0     | ... |  CHUNK N (FINAL)  |
+//! +------------+-----------+------------+----------------+-----+-------------------+
+//! |  [u8; 24]  | [u8; 17]  |  [u8; 17]  | [u8; 64K + 17] |     | [u8; <= 64K + 17] |
+//! +------------+-----------+------------+----------------+-----+-------------------+
+//! ```
+//!
+//! Reordered or modified chunks fail to authenticate, and a stream that ends
+//! before its `Final` chunk is rejected as truncated.
+//!
+//! Tarboxes with an authenticated header start the stream with two empty
+//! chunks. The first carries no associated data, so failing to open it means
+//! the key is wrong. The second carries the raw tarbox header as associated
+//! data, so failing to open it means the header was modified. Key slots are
+//! left out of the header here, see `tarbox::attributes::authenticated_header`.
+
 use sodiumoxide::crypto::secretbox;
+use sodiumoxide::crypto::secretstream::{
+    Header, Key, Pull, Push, Stream, Tag, ABYTES, HEADERBYTES,
+};
+use std::cmp;
+use std::io;
+use std::io::{Cursor, Read, Write};
+
+use super::{errors, errors::ResultExt, tarbox, tarbox::attributes::Cipher};
+
+/// Size of the plaintext held by each encrypted chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Encrypts everything written to it into `inner` as a chunked `secretstream`.
+/// `finish` must be called to seal the final chunk.
+pub struct Encryptor<W: Write> {
+    inner: W,
+    stream: Stream<Push>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Encryptor<W> {
+    /// Starts a new stream keyed from `secret` and writes its header to
+    /// `inner`, followed by the chunks that authenticate the raw tarbox
+    /// `header`.
+    pub fn new(
+        inner: W,
+        secret: &tarbox::TarboxSecret,
+        header: &[u8],
+    ) -> errors::Result<Encryptor<W>> {
+        Encryptor::with_key(inner, &secret.stream_key()?, header)
+    }
+
+    /// Like `new`, but encrypts with `key` as is.
+    pub fn with_key(mut inner: W, key: &Key, header: &[u8]) -> errors::Result<Encryptor<W>> {
+        let (stream, stream_header) = match Stream::init_push(key) {
+            Ok(init) => init,
+            Err(_) => bail!(errors::ErrorKind::SecretStreamInitFail),
+        };
+        inner.write_all(&stream_header.0)?;
+
+        let mut enc = Encryptor {
+            inner: inner,
+            stream: stream,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        enc.push_empty(None)?;
+        enc.push_empty(Some(header))?;
+
+        Ok(enc)
+    }
+
+    fn push_empty(&mut self, ad: Option<&[u8]>) -> errors::Result<()> {
+        let sealed = match self.stream.push(&[], ad, Tag::Message) {
+            Ok(sealed) => sealed,
+            Err(_) => bail!(errors::ErrorKind::SecretStreamInitFail),
+        };
+        self.inner.write_all(sealed.as_slice())?;
+        Ok(())
+    }
 
-use super::{errors, tarbox, BufResult};
+    fn push_chunk(&mut self, tag: Tag) -> io::Result<()> {
+        let sealed = self
+            .stream
+            .push(self.buf.as_slice(), None, tag)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "could not seal chunk"))?;
+        self.inner.write_all(sealed.as_slice())?;
+        self.buf.clear();
+        Ok(())
+    }
 
-pub fn encrypt_buffer(buf: &Vec<u8>, secret: &tarbox::TarboxSecret) -> BufResult {
-    Ok(secretbox::seal(
-        buf.as_slice(),
-        &secret.nonce(),
-        &secret.key(),
-    ))
+    /// Seals whatever is left as the final chunk and hands back the
+    /// inner writer.
+    pub fn finish(mut self) -> errors::Result<W> {
+        self.push_chunk(Tag::Final)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
 }
 
-pub fn decrypt_buffer(buf: &Vec<u8>, secret: &tarbox::TarboxSecret) -> BufResult {
-    secretbox::open(buf.as_slice(), &secret.nonce(), &secret.key())
-        .or_else(|_| bail!(errors::ErrorKind::SecretBoxOpenFail))
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = cmp::min(CHUNK_SIZE - self.buf.len(), buf.len());
+        self.buf.extend_from_slice(&buf[..take]);
+
+        if self.buf.len() == CHUNK_SIZE {
+            self.push_chunk(Tag::Message)?;
+        }
+
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a chunked `secretstream` read from `inner`.
+pub struct Decryptor<R: Read> {
+    inner: R,
+    stream: Stream<Pull>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Decryptor<R> {
+    /// Reads the stream header from `inner` and opens the first chunk, so a
+    /// wrong secret is reported here as `SecretStreamOpenFail` rather than
+    /// partway through the archive.
+    ///
+    /// If `header` is given, it is checked against the stream and a mismatch
+    /// is reported as `HeaderAuthFail`, while a damaged first chunk after
+    /// that is reported as `CorruptArchive`. Without `header`, a stream that
+    /// was sealed with an authenticated header is reported as
+    /// `HeaderAuthFail` too.
+    pub fn new(
+        inner: R,
+        secret: &tarbox::TarboxSecret,
+        header: Option<&[u8]>,
+    ) -> errors::Result<Decryptor<R>> {
+        Decryptor::with_key(inner, &secret.stream_key()?, header)
+    }
+
+    /// Like `new`, but decrypts with `key` as is.
+    pub fn with_key(
+        mut inner: R,
+        key: &Key,
+        header: Option<&[u8]>,
+    ) -> errors::Result<Decryptor<R>> {
+        let mut stream_header = [0; HEADERBYTES];
+        inner.read_exact(&mut stream_header)?;
+
+        let stream = match Stream::init_pull(&Header(stream_header), key) {
+            Ok(stream) => stream,
+            Err(_) => bail!(errors::ErrorKind::SecretStreamInitFail),
+        };
+
+        let mut dec = Decryptor {
+            inner: inner,
+            stream: stream,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+
+        match header {
+            Some(header) => {
+                if !dec.pull_empty(None)? {
+                    bail!(errors::ErrorKind::SecretStreamOpenFail);
+                }
+                if !dec.pull_empty(Some(header))? {
+                    bail!(errors::ErrorKind::HeaderAuthFail);
+                }
+                // The key is known to be right, so the payload is damaged
+                dec.next_chunk().chain_err(|| errors::ErrorKind::CorruptArchive)?;
+            }
+            None => dec.first_chunk()?,
+        }
+
+        Ok(dec)
+    }
+
+    /// Opens the first chunk of a stream whose header is not authenticated.
+    /// A stream that starts with a key check chunk instead belongs to a
+    /// tarbox whose header version was lowered to skip the header check.
+    fn first_chunk(&mut self) -> errors::Result<()> {
+        let sealed = self.read_chunk()?;
+        match self.open_chunk(&sealed) {
+            Err(errors::Error(errors::ErrorKind::SecretStreamOpenFail, _))
+                if sealed.len() >= ABYTES && self.is_key_check(&sealed[..ABYTES]) =>
+            {
+                bail!(errors::ErrorKind::HeaderAuthFail)
+            }
+            res => res,
+        }
+    }
+
+    fn is_key_check(&mut self, sealed: &[u8]) -> bool {
+        match self.stream.pull(sealed, None) {
+            Ok((ref chunk, Tag::Message)) => chunk.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Opens one of the empty chunks at the front of the stream, returning
+    /// whether it authenticated.
+    fn pull_empty(&mut self, ad: Option<&[u8]>) -> errors::Result<bool> {
+        let mut sealed = [0; ABYTES];
+        if read_full(&mut self.inner, &mut sealed)? != ABYTES {
+            bail!(errors::ErrorKind::SecretStreamTruncated);
+        }
+
+        match self.stream.pull(&sealed, ad) {
+            Ok((ref chunk, Tag::Message)) if chunk.is_empty() => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn next_chunk(&mut self) -> errors::Result<()> {
+        let sealed = self.read_chunk()?;
+        self.open_chunk(&sealed)
+    }
+
+    fn read_chunk(&mut self) -> errors::Result<Vec<u8>> {
+        let mut sealed = vec![0; CHUNK_SIZE + ABYTES];
+        let read = read_full(&mut self.inner, sealed.as_mut_slice())?;
+        if read == 0 {
+            bail!(errors::ErrorKind::SecretStreamTruncated);
+        }
+
+        sealed.truncate(read);
+        Ok(sealed)
+    }
+
+    fn open_chunk(&mut self, sealed: &[u8]) -> errors::Result<()> {
+        let (chunk, tag) = match self.stream.pull(sealed, None) {
+            Ok(opened) => opened,
+            Err(_) => bail!(errors::ErrorKind::SecretStreamOpenFail),
+        };
+
+        if tag == Tag::Final {
+            // Nothing may follow the final chunk
+            let mut trailing = [0; 1];
+            if read_full(&mut self.inner, &mut trailing)? != 0 {
+                bail!(errors::ErrorKind::SecretStreamTrailingData);
+            }
+        }
+
+        self.chunk = chunk;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, outbuf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.stream.is_finalized() {
+                return Ok(0);
+            }
+
+            self.next_chunk().map_err(into_io_error)?;
+        }
+
+        let size = cmp::min(outbuf.len(), self.chunk.len() - self.pos);
+        outbuf[..size].copy_from_slice(&self.chunk[self.pos..self.pos + size]);
+        self.pos += size;
+
+        Ok(size)
+    }
+}
+
+/// Reads the `secretbox` of a version 1 tarbox out of `inner` and opens it,
+/// yielding a reader over the decrypted payload. `secretbox` has no streaming
+/// mode, so the whole box is held in memory.
+pub fn open_secretbox<R: Read>(
+    mut inner: R,
+    secret: &tarbox::TarboxSecret,
+    nonce: &tarbox::secret::Nonce,
+) -> errors::Result<Cursor<Vec<u8>>> {
+    let mut buf = Vec::new();
+    inner.read_to_end(&mut buf)?;
+
+    debug!("decrypting compressed buf (size {})", buf.len());
+    let buf = match secretbox::open(buf.as_slice(), nonce, &secret.key()?) {
+        Ok(buf) => buf,
+        Err(_) => bail!(errors::ErrorKind::SecretBoxOpenFail),
+    };
+
+    Ok(Cursor::new(buf))
+}
+
+/// Returns a reader over the decrypted payload of a tarbox, picking the
+/// construction based on the cipher recorded in `attrs`. `header` is the
+/// raw tarbox header, checked against the stream when `attrs` says it is
+/// authenticated.
+pub fn decryptor<'a, R: Read + 'a>(
+    inner: R,
+    attrs: &tarbox::Attributes,
+    secret: &tarbox::TarboxSecret,
+    header: &[u8],
+) -> errors::Result<Box<dyn Read + 'a>> {
+    match attrs.cipher() {
+        Cipher::XSalsa20Poly1305 => {
+            // Only version 1 headers use `secretbox`, and they always carry a nonce
+            let nonce = tarbox::secret::Nonce::from_slice(attrs.nonce().unwrap()).unwrap();
+            Ok(Box::new(open_secretbox(inner, secret, &nonce)?))
+        }
+        Cipher::XChaCha20Poly1305 => {
+            let header = if attrs.authenticates_header() {
+                Some(header)
+            } else {
+                None
+            };
+            Ok(Box::new(Decryptor::new(inner, secret, header)?))
+        }
+    }
+}
+
+/// Fills `buf` from `inner`, stopping early only at end of stream.
+fn read_full<R: Read>(inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match inner.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(read)
+}
+
+fn into_io_error(err: errors::Error) -> io::Error {
+    match err {
+        errors::Error(errors::ErrorKind::Io(e), _) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use password;
+    use tarbox::TarboxSecret;
+
+    const HEADER: &[u8] = b"tarbox header";
+
+    // Stream header plus the key check and header chunks
+    const PREFIX: usize = HEADERBYTES + 2 * ABYTES;
+
+    fn seal(secret: &TarboxSecret, data: &[u8]) -> Vec<u8> {
+        let mut enc = Encryptor::new(Vec::new(), secret, HEADER).unwrap();
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    fn open(secret: &TarboxSecret, sealed: &[u8], header: &[u8]) -> errors::Result<Vec<u8>> {
+        let mut dec = Decryptor::new(sealed, secret, Some(header))?;
+        let mut data = Vec::new();
+        dec.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn make_data() -> Vec<u8> {
+        (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let secret = TarboxSecret::generate(password::generate_password());
+        let data = make_data();
+        let sealed = seal(&secret, &data);
+
+        // prefix + 3 chunks, each with its own tag
+        assert_eq!(sealed.len(), PREFIX + data.len() + 3 * ABYTES);
+        assert_eq!(open(&secret, &sealed, HEADER).unwrap(), data);
+    }
+
+    #[test]
+    fn test_wrong_secret() {
+        let secret = TarboxSecret::generate(password::generate_password());
+        let sealed = seal(&secret, &make_data());
+
+        let other = TarboxSecret::generate(password::generate_password());
+        match open(&other, &sealed, HEADER) {
+            Err(errors::Error(errors::ErrorKind::SecretStreamOpenFail, _)) => (),
+            res => panic!(format!("expected `SecretStreamOpenFail`, got: {:?}", res)),
+        }
+    }
+
+    #[test]
+    fn test_modified_header() {
+        let secret = TarboxSecret::generate(password::generate_password());
+        let sealed = seal(&secret, &make_data());
+
+        match open(&secret, &sealed, b"tarbox headeR") {
+            Err(errors::Error(errors::ErrorKind::HeaderAuthFail, _)) => (),
+            res => panic!(format!("expected `HeaderAuthFail`, got: {:?}", res)),
+        }
+    }
+
+    #[test]
+    fn test_downgraded_header() {
+        let secret = TarboxSecret::generate(password::generate_password());
+        let sealed = seal(&secret, &make_data());
+
+        // Read as if the header version said it was not authenticated
+        match Decryptor::new(sealed.as_slice(), &secret, None) {
+            Err(errors::Error(errors::ErrorKind::HeaderAuthFail, _)) => (),
+            Err(e) => panic!(format!("expected `HeaderAuthFail`, got: {:?}", e)),
+            Ok(_) => panic!("expected `HeaderAuthFail`, got a stream"),
+        }
+
+        let other = TarboxSecret::generate(password::generate_password());
+        match Decryptor::new(sealed.as_slice(), &other, None) {
+            Err(errors::Error(errors::ErrorKind::SecretStreamOpenFail, _)) => (),
+            Err(e) => panic!(format!("expected `SecretStreamOpenFail`, got: {:?}", e)),
+            Ok(_) => panic!("expected `SecretStreamOpenFail`, got a stream"),
+        }
+    }
+
+    #[test]
+    fn test_corrupt_payload() {
+        let secret = TarboxSecret::generate(password::generate_password());
+        let mut sealed = seal(&secret, &make_data());
+        sealed[PREFIX + 10] ^= 0x1;
+
+        match open(&secret, &sealed, HEADER) {
+            Err(errors::Error(errors::ErrorKind::CorruptArchive, _)) => (),
+            res => panic!(format!("expected `CorruptArchive`, got: {:?}", res)),
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let secret = TarboxSecret::generate(password::generate_password());
+        let sealed = seal(&secret, &make_data());
+
+        // Drop the final chunk entirely
+        let truncated = &sealed[..PREFIX + 2 * (CHUNK_SIZE + ABYTES)];
+        assert!(open(&secret, truncated, HEADER).is_err());
+    }
+
+    #[test]
+    fn test_reordered() {
+        let secret = TarboxSecret::generate(password::generate_password());
+        let sealed = seal(&secret, &make_data());
+
+        // Swap the first two chunks
+        let chunk = CHUNK_SIZE + ABYTES;
+        let mut reordered = sealed[..PREFIX].to_vec();
+        reordered.extend_from_slice(&sealed[PREFIX + chunk..PREFIX + 2 * chunk]);
+        reordered.extend_from_slice(&sealed[PREFIX..PREFIX + chunk]);
+        reordered.extend_from_slice(&sealed[PREFIX + 2 * chunk..]);
+
+        assert!(open(&secret, &reordered, HEADER).is_err());
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let secret = TarboxSecret::generate(password::generate_password());
+        let mut sealed = seal(&secret, &make_data());
+        sealed.push(0x0);
+
+        assert!(open(&secret, &sealed, HEADER).is_err());
+    }
 }
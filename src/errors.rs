@@ -5,23 +5,205 @@ error_chain! {
 
     foreign_links {
         Fmt(::std::fmt::Error);
+        Glob(::globset::Error);
+        Ignore(::ignore::Error);
         Io(::std::io::Error);
     }
 
     errors {
+        CorruptArchive {
+            description("archive inside the tarbox is corrupt"),
+            display("archive inside the tarbox is corrupt"),
+        }
+
+        DeviceEntry(path: String) {
+            description("archive contains a device node"),
+            display("archive contains a device node: {}", path),
+        }
+
+        DuplicateEntryName(name: String) {
+            description("two paths would be archived under the same name"),
+            display("more than one path would be archived as: {}", name),
+        }
+
+        DuplicateShare(position: usize) {
+            description("share given more than once"),
+            display("share {} was given more than once", position),
+        }
+
+        FileExists(path: String) {
+            description("archive entry would replace an existing file"),
+            display("archive entry would replace an existing file: {}", path),
+        }
+
+        HeaderAuthFail {
+            description("tarbox header failed authentication"),
+            display("tarbox header failed authentication; it may have been modified"),
+        }
+
+        IdentityRequired {
+            description("tarbox is sealed for recipients"),
+            display("tarbox is sealed for recipients; open it with an identity"),
+        }
+
+        InconsistentShares {
+            description("shares do not agree on the secret"),
+            display("shares do not agree on the secret; one of them is wrong"),
+        }
+
+        InvalidCompressionLevel(compression: String, level: u32) {
+            description("compression level out of range"),
+            display("{} does not support compression level {}", compression, level),
+        }
+
+        InvalidShare(position: usize) {
+            description("share is mistyped or damaged"),
+            display("share {} is mistyped or damaged", position),
+        }
+
+        InvalidShareThreshold(threshold: usize, shares: usize) {
+            description("invalid share threshold"),
+            display("cannot require {} of {} shares; use 2 up to the number of shares, at most 255", threshold, shares),
+        }
+
+        KeyfileRequired {
+            description("tarbox needs a keyfile"),
+            display("tarbox needs a keyfile to open; pass it with --keyfile"),
+        }
+
+        KeyfileUnsupported(version: u8) {
+            description("tarbox predates keyfiles"),
+            display("version {} tarboxes are not opened with a keyfile; drop --keyfile", version),
+        }
+
+        LinkEscape(path: String, target: String) {
+            description("archive link points outside the destination"),
+            display("archive link {} points outside the destination: {}", path, target),
+        }
+
+        MismatchedShares(position: usize) {
+            description("shares belong to different secrets"),
+            display("share {} belongs to a different secret than the first", position),
+        }
+
         MissingField(name: String) {
             description("field missing during build"),
             display("field missing during build: {}", name),
         }
 
+        MistypedSecret {
+            description("secret looks mistyped"),
+            display("secret looks mistyped; its last word does not match the others"),
+        }
+
+        MistypedWord(position: usize, nearest: String) {
+            description("word of the secret looks mistyped"),
+            display("word {} of the secret looks mistyped; nearest dictionary words: {}", position, nearest),
+        }
+
+        NoEntryName(path: String) {
+            description("path has no name to archive it under"),
+            display("path has no name to archive it under: {}", path),
+        }
+
+        MemberNotFile(path: String) {
+            description("archive member is not a regular file"),
+            display("archive member is not a regular file: {}", path),
+        }
+
+        MemberNotFound(path: String) {
+            description("archive member not found"),
+            display("archive member not found: {}", path),
+        }
+
+        NoKeySlots {
+            description("tarbox would have no key slots"),
+            display("nothing would open the tarbox; seal it with a secret, a keyfile or for a recipient"),
+        }
+
+        NoSecretToReplace {
+            description("tarbox has no secret key slots"),
+            display("tarbox has no key slot opened by a secret to replace"),
+        }
+
+        NotARecipient {
+            description("identity is not a recipient of the tarbox"),
+            display("identity is not a recipient of the tarbox"),
+        }
+
+        NotEnoughShares(needed: usize, given: usize) {
+            description("not enough shares to recover the secret"),
+            display("{} shares are needed to recover the secret, {} given", needed, given),
+        }
+
+        NotSingleFile {
+            description("tarbox does not hold exactly one file"),
+            display("tarbox does not hold exactly one file"),
+        }
+
+        OutputPathRequired {
+            description("an output path is required for these inputs"),
+            display("inputs have no common parent to name the tarbox after; use --output"),
+        }
+
         PathDoesNotExist(path: String) {
             description("the file or directory specified does not exist"),
             display("file or directory does not exist: {}", path),
         }
 
+        RekeyUnsupported(version: u8) {
+            description("tarbox has no key slots to rekey"),
+            display("version {} tarboxes have no key slots to rekey; unseal and seal it again", version),
+        }
+
         SecretBoxOpenFail {
             description("could not open secretbox"),
             display("could not open secretbox"),
         }
+
+        SecretRequired {
+            description("tarbox is sealed with a secret"),
+            display("tarbox is sealed with a secret, not for recipients"),
+        }
+
+        SecretStreamInitFail {
+            description("could not initialize secretstream"),
+            display("could not initialize secretstream"),
+        }
+
+        SecretStreamOpenFail {
+            description("could not open secretstream chunk"),
+            display("could not open secretstream chunk"),
+        }
+
+        SecretStreamTrailingData {
+            description("unexpected data after final secretstream chunk"),
+            display("unexpected data after final secretstream chunk"),
+        }
+
+        SecretStreamTruncated {
+            description("secretstream ended before its final chunk"),
+            display("secretstream ended before its final chunk"),
+        }
+
+        StdinWithPaths {
+            description("stdin cannot be sealed together with other paths"),
+            display("`-` (stdin) cannot be sealed together with other paths"),
+        }
+
+        TooManyKeySlots(slots: usize) {
+            description("too many key slots or too costly a KDF"),
+            display("{} key slots are too many, or too costly to try, for unseal; use fewer secrets and recipients or a cheaper KDF", slots),
+        }
+
+        UnsafeEntryPath(path: String) {
+            description("archive entry would be written outside the destination"),
+            display("archive entry would be written outside the destination: {}", path),
+        }
+
+        WrongSecret {
+            description("secret does not open the tarbox"),
+            display("no key slot of the tarbox opens with this secret or keyfile"),
+        }
     }
 }
@@ -0,0 +1,202 @@
+//! Glob filters that decide which archive paths are packed or unpacked.
+//!
+//! A pattern without a `/` is matched against the last component of a
+//! path, so `*.swp` and `node_modules` match at any depth. A pattern with a
+//! `/` is matched against the whole path relative to the archive root; a
+//! leading `/` is only an anchor and is dropped. `*` never crosses a `/`,
+//! while `**` does.
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use super::errors;
+
+#[derive(Clone, Debug)]
+struct GlobList {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl GlobList {
+    fn new(patterns: &[String]) -> errors::Result<GlobList> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            let pattern = pattern.trim_end_matches('/');
+            let glob = GlobBuilder::new(pattern.trim_start_matches('/'))
+                .literal_separator(true)
+                .build()?;
+
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+
+        Ok(GlobList {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty()
+    }
+
+    fn is_match(&self, path: &Path) -> bool {
+        let name_match = path
+            .file_name()
+            .map(|name| self.names.is_match(name))
+            .unwrap_or(false);
+
+        name_match || self.paths.is_match(path)
+    }
+}
+
+/// Excludes paths matching any exclude pattern and, when include patterns
+/// are given, keeps only the files matching one of them.
+#[derive(Clone, Debug)]
+pub struct PathFilter {
+    excludes: GlobList,
+    includes: GlobList,
+}
+
+impl PathFilter {
+    pub fn new(excludes: &[String], includes: &[String]) -> errors::Result<PathFilter> {
+        Ok(PathFilter {
+            excludes: GlobList::new(excludes)?,
+            includes: GlobList::new(includes)?,
+        })
+    }
+
+    /// A filter that lets everything through.
+    pub fn empty() -> PathFilter {
+        PathFilter::new(&[], &[]).unwrap()
+    }
+
+    pub fn has_includes(&self) -> bool {
+        !self.includes.is_empty()
+    }
+
+    /// Whether `path` matches an exclude pattern. Excluded directories are
+    /// skipped along with everything below them.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.excludes.is_match(path)
+    }
+
+    /// Whether the file at `path` should be kept: it must not be excluded
+    /// and, if there are include patterns, must match one of them.
+    pub fn is_included(&self, path: &Path) -> bool {
+        if self.is_excluded(path) {
+            return false;
+        }
+
+        !self.has_includes() || self.includes.is_match(path)
+    }
+}
+
+/// Decides whether an archive entry, given its normalized path, is unpacked.
+pub type EntryFilter = Box<dyn Fn(&Path) -> bool>;
+
+/// Builds a filter that selects the archive `members` named on the command
+/// line. A member is a path or a glob matched against the whole entry path;
+/// when it names a directory, everything below it is selected too.
+pub fn members(members: &[String]) -> errors::Result<EntryFilter> {
+    let mut globs = GlobSetBuilder::new();
+    for member in members {
+        let member = member.trim_start_matches("./").trim_end_matches('/');
+        globs.add(GlobBuilder::new(member).literal_separator(true).build()?);
+    }
+    let globs = globs.build()?;
+
+    Ok(Box::new(move |path: &Path| {
+        path.ancestors()
+            .any(|p| !p.as_os_str().is_empty() && globs.is_match(p))
+    }))
+}
+
+/// Reads patterns from `path`, one per line. Blank lines and lines
+/// starting with `#` are ignored.
+pub fn read_pattern_file(path: &Path) -> errors::Result<Vec<String>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut patterns = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        patterns.push(line.to_string());
+    }
+
+    Ok(patterns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{members, PathFilter};
+    use std::path::Path;
+
+    fn patterns(list: &[&str]) -> Vec<String> {
+        list.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_excludes() {
+        let filter = PathFilter::new(
+            &patterns(&[".git", "node_modules/", "*.swp", "/build/*.o"]),
+            &[],
+        ).unwrap();
+
+        // (path, excluded)
+        let results = [
+            ("app/.git", true),
+            ("app/web/node_modules", true),
+            ("app/src/.main.rs.swp", true),
+            ("build/main.o", true),
+            ("app/build/main.o", false),
+            ("app/src/main.rs", false),
+            ("app/.gitignore", false),
+        ];
+
+        for (path, excluded) in results.iter() {
+            assert_eq!(*excluded, filter.is_excluded(Path::new(path)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_includes() {
+        let filter = PathFilter::new(&patterns(&["secret.conf"]), &patterns(&["*.conf"])).unwrap();
+
+        assert!(filter.has_includes());
+        assert!(filter.is_included(Path::new("nginx/sites/default.conf")));
+        assert!(!filter.is_included(Path::new("nginx/secret.conf")));
+        assert!(!filter.is_included(Path::new("nginx/README")));
+
+        assert!(PathFilter::empty().is_included(Path::new("nginx/README")));
+    }
+
+    #[test]
+    fn test_members() {
+        let filter = members(&patterns(&["./etc/nginx/", "*.pem"])).unwrap();
+
+        // (path, selected)
+        let results = [
+            ("etc/nginx", true),
+            ("etc/nginx/sites/default", true),
+            ("etc/nginx.conf", false),
+            ("cert.pem", true),
+            ("ssl/cert.pem", false),
+        ];
+
+        for (path, selected) in results.iter() {
+            assert_eq!(*selected, filter(Path::new(path)), "{}", path);
+        }
+    }
+}
@@ -1,32 +1,636 @@
-use libflate::gzip::{Decoder, Encoder};
-use std::io::{Read, Write};
-use std::vec::Vec;
+use libflate::gzip;
+use libflate::lz77::{self, DefaultLz77Encoder};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
 
-use super::BufResult;
+use super::errors;
+use super::tarbox::attributes::Compression;
 
-pub fn compress_buffer(buf: &Vec<u8>) -> BufResult {
-    let mut compressor = Encoder::new(Vec::new())?;
-    compressor.write_all(buf.as_slice())?;
+/// Amount of the stream that is buffered and trial-compressed to decide
+/// whether it is worth compressing.
+pub const SAMPLE_SIZE: usize = 1024 * 1024;
 
-    debug!("write {} bytes into compressor", buf.len());
+/// Samples smaller than this are always compressed; they say too little
+/// about the data.
+const MIN_SAMPLE_SIZE: usize = 4 * 1024;
 
+/// Smallest size reduction, in percent of the sample, for which the data
+/// is compressed.
+const MIN_GAIN_PERCENT: usize = 5;
+
+/// Size of the blocks the archive is split into. Each block is compressed
+/// on its own, so blocks can be compressed in parallel and the output does
+/// not depend on the number of threads.
+///
+/// Only compression runs on the worker threads. The secretstream chains
+/// every chunk to the one before it, so encryption stays on the calling
+/// thread; it is much cheaper than compression anyway.
+pub const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// A compression algorithm along with the level it runs at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Codec {
+    compression: Compression,
+    level: Option<u32>,
+    skip_incompressible: bool,
+    threads: usize,
+}
+
+impl Codec {
+    /// Returns a codec for `compression` at `level`, or at the algorithm's
+    /// default level when `level` is `None`.
+    pub fn new(compression: Compression, level: Option<u32>) -> errors::Result<Codec> {
+        if let Some(level) = level {
+            match compression.levels() {
+                Some((low, high)) if level >= low && level <= high => (),
+                _ => bail!(errors::ErrorKind::InvalidCompressionLevel(
+                    compression.name().to_string(),
+                    level
+                )),
+            }
+        }
+
+        Ok(Codec {
+            compression: compression,
+            level: level,
+            skip_incompressible: true,
+            threads: 1,
+        })
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn level(&self) -> Option<u32> {
+        self.level
+    }
+
+    /// Whether data that barely compresses is stored uncompressed instead.
+    /// On by default.
+    pub fn skip_incompressible(&self) -> bool {
+        self.skip_incompressible
+    }
+
+    pub fn set_skip_incompressible(&mut self, skip: bool) {
+        self.skip_incompressible = skip;
+    }
+
+    /// Number of threads blocks are compressed on.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = cmp::max(threads, 1);
+    }
+
+    /// Size of the independently compressed blocks, or `None` when the data
+    /// is stored uncompressed.
+    pub fn block_size(&self) -> Option<u32> {
+        match self.compression {
+            Compression::None => None,
+            _ => Some(BLOCK_SIZE as u32),
+        }
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec {
+            compression: Compression::Gzip,
+            level: None,
+            skip_incompressible: true,
+            threads: 1,
+        }
+    }
+}
+
+/// Compressing writer returned by `compressor`.
+pub enum Compressor<W: Write> {
+    Gzip(gzip::Encoder<W>),
+    Zstd(ZstdEncoder<W>),
+    Xz(XzEncoder<W>),
+    None(W),
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Compressor::Gzip(ref mut w) => w.write(buf),
+            Compressor::Zstd(ref mut w) => w.write(buf),
+            Compressor::Xz(ref mut w) => w.write(buf),
+            Compressor::None(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Compressor::Gzip(ref mut w) => w.flush(),
+            Compressor::Zstd(ref mut w) => w.flush(),
+            Compressor::Xz(ref mut w) => w.flush(),
+            Compressor::None(ref mut w) => w.flush(),
+        }
+    }
+}
+
+/// Wraps `inner` in a compressor for `codec`. Call `finish_compressor`
+/// once all data has been written to close out the compressed stream.
+pub fn compressor<W: Write>(inner: W, codec: &Codec) -> errors::Result<Compressor<W>> {
+    let compressor = match codec.compression {
+        Compression::Gzip => {
+            // A fixed modification time keeps the output reproducible.
+            let header = gzip::HeaderBuilder::new().modification_time(0).finish();
+            let options = match codec.level {
+                Some(0) => gzip::EncodeOptions::new().no_compression(),
+                Some(level) => gzip::EncodeOptions::with_lz77(gzip_lz77(level)),
+                None => gzip::EncodeOptions::new(),
+            };
+            let options = options.header(header);
+            Compressor::Gzip(gzip::Encoder::with_options(inner, options)?)
+        }
+        Compression::Zstd => {
+            let level = codec.level.unwrap_or(3) as i32;
+            Compressor::Zstd(ZstdEncoder::new(inner, level)?)
+        }
+        Compression::Xz => Compressor::Xz(XzEncoder::new(inner, codec.level.unwrap_or(6))),
+        Compression::None => Compressor::None(inner),
+    };
+
+    Ok(compressor)
+}
+
+/// libflate has no gzip levels as such, so levels 1 to 9 pick how far back
+/// it looks for matches: from 128 bytes at level 1 to the whole 32 KiB
+/// window at level 9, which is also what no level gets.
+fn gzip_lz77(level: u32) -> DefaultLz77Encoder {
+    let window_size = u32::from(lz77::MAX_WINDOW_SIZE) >> (9 - level);
+    DefaultLz77Encoder::with_window_size(window_size as u16)
+}
+
+pub fn finish_compressor<W: Write>(compressor: Compressor<W>) -> errors::Result<W> {
     // Finish the compression stream
-    let (buf, err) = compressor.finish().unwrap();
-    if let Some(e) = err {
-        bail!(e);
+    let inner = match compressor {
+        Compressor::Gzip(w) => {
+            let (inner, err) = w.finish().unwrap();
+            if let Some(e) = err {
+                bail!(e);
+            }
+            inner
+        }
+        Compressor::Zstd(w) => w.finish()?,
+        Compressor::Xz(w) => w.finish()?,
+        Compressor::None(w) => w,
+    };
+
+    Ok(inner)
+}
+
+fn compress_block(codec: &Codec, block: &[u8]) -> errors::Result<Vec<u8>> {
+    let mut compressor = compressor(Vec::with_capacity(block.len() / 2), codec)?;
+    compressor.write_all(block)?;
+    finish_compressor(compressor)
+}
+
+type Job = (usize, Vec<u8>);
+type Done = (usize, errors::Result<Vec<u8>>);
+
+/// Pool of threads compressing blocks handed to it in order.
+struct Workers {
+    jobs: Option<mpsc::Sender<Job>>,
+    done: mpsc::Receiver<Done>,
+    handles: Vec<thread::JoinHandle<()>>,
+}
+
+impl Workers {
+    fn new(codec: Codec) -> Workers {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (results, done) = mpsc::channel::<Done>();
+        let queue = Arc::new(Mutex::new(queue));
+
+        let handles = (0..codec.threads)
+            .map(|_| {
+                let queue = queue.clone();
+                let results = results.clone();
+                thread::spawn(move || loop {
+                    // A poisoned queue means another worker died; leave
+                    // the remaining jobs to the caller's error path.
+                    let job = match queue.lock() {
+                        Ok(queue) => queue.recv(),
+                        Err(_) => break,
+                    };
+                    let (index, block) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // Report a panic as a failed block rather than losing
+                    // the job and leaving the caller waiting for it.
+                    let compressed =
+                        panic::catch_unwind(AssertUnwindSafe(|| compress_block(&codec, &block)))
+                            .unwrap_or_else(|_| Err("compression worker panicked".into()));
+                    if results.send((index, compressed)).is_err() {
+                        break;
+                    }
+                })
+            }).collect();
+
+        Workers {
+            jobs: Some(jobs),
+            done: done,
+            handles: handles,
+        }
     }
+}
 
-    Ok(buf)
+/// Compressing writer returned by `block_compressor`.
+pub struct BlockCompressor<W: Write> {
+    inner: W,
+    codec: Codec,
+    block: Vec<u8>,
+    workers: Option<Workers>,
+    sent: usize,
+    written: usize,
+    pending: BTreeMap<usize, Vec<u8>>,
 }
 
-pub fn inflate_buffer(buf: &Vec<u8>) -> BufResult {
-    let mut inflater = Decoder::new(buf.as_slice())?;
+impl<W: Write> BlockCompressor<W> {
+    fn push_block(&mut self) -> errors::Result<()> {
+        let block = mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE));
+
+        if self.codec.compression == Compression::None {
+            self.inner.write_all(&block)?;
+            return Ok(());
+        }
 
-    let mut outbuf = Vec::new();
-    let read_sz = inflater.read_to_end(&mut outbuf)?;
+        let jobs = match self.workers {
+            Some(ref workers) => workers.jobs.as_ref().unwrap(),
+            None => {
+                self.inner.write_all(&compress_block(&self.codec, &block)?)?;
+                self.sent += 1;
+                self.written += 1;
+                return Ok(());
+            }
+        };
 
-    debug!("read {} bytes from inflater", read_sz);
+        if jobs.send((self.sent, block)).is_err() {
+            bail!("compression worker exited");
+        }
+        self.sent += 1;
 
-    // Finish the inflation stream
-    Ok(outbuf)
+        // Keep every worker busy without queueing up the whole input.
+        while self.sent - self.written >= 2 * self.codec.threads {
+            self.write_done()?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for a compressed block and writes out every block that is
+    /// next in line.
+    fn write_done(&mut self) -> errors::Result<()> {
+        let (index, compressed) = match self.workers {
+            Some(ref workers) => match workers.done.recv() {
+                Ok(done) => done,
+                Err(_) => bail!("compression worker exited"),
+            },
+            None => return Ok(()),
+        };
+        self.pending.insert(index, compressed?);
+
+        while let Some(compressed) = self.pending.remove(&self.written) {
+            self.inner.write_all(&compressed)?;
+            self.written += 1;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BlockCompressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = cmp::min(BLOCK_SIZE - self.block.len(), buf.len());
+        self.block.extend_from_slice(&buf[..take]);
+
+        if self.block.len() == BLOCK_SIZE {
+            self.push_block()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        }
+
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Wraps `inner` in a compressor that splits the stream into `BLOCK_SIZE`
+/// blocks and compresses each one on its own, on `codec.threads()` worker
+/// threads. Blocks are written out in order, so the output is the same
+/// whatever the number of threads. Call `finish_block_compressor` once all
+/// data has been written.
+pub fn block_compressor<W: Write>(inner: W, codec: &Codec) -> BlockCompressor<W> {
+    let workers = match (codec.compression, codec.threads) {
+        (Compression::None, _) | (_, 1) => None,
+        _ => Some(Workers::new(*codec)),
+    };
+
+    BlockCompressor {
+        inner: inner,
+        codec: *codec,
+        block: Vec::with_capacity(BLOCK_SIZE),
+        workers: workers,
+        sent: 0,
+        written: 0,
+        pending: BTreeMap::new(),
+    }
+}
+
+pub fn finish_block_compressor<W: Write>(mut compressor: BlockCompressor<W>) -> errors::Result<W> {
+    // Even an empty stream is written as one block, so that it decodes.
+    if !compressor.block.is_empty() || compressor.sent == 0 {
+        compressor.push_block()?;
+    }
+
+    while compressor.written < compressor.sent {
+        compressor.write_done()?;
+    }
+
+    if let Some(mut workers) = compressor.workers.take() {
+        workers.jobs.take();
+        for handle in workers.handles.drain(..) {
+            if handle.join().is_err() {
+                bail!("compression worker panicked");
+            }
+        }
+    }
+
+    Ok(compressor.inner)
+}
+
+/// Trial-compresses `sample` with `codec` and tells whether the gain is
+/// worth it.
+pub fn worth_compressing(codec: &Codec, sample: &[u8]) -> errors::Result<bool> {
+    if codec.compression == Compression::None || sample.len() < MIN_SAMPLE_SIZE {
+        return Ok(true);
+    }
+
+    let mut trial = compressor(Vec::new(), codec)?;
+    trial.write_all(sample)?;
+    let trial = finish_compressor(trial)?;
+
+    let saved = sample.len().saturating_sub(trial.len());
+    Ok(saved * 100 >= sample.len() * MIN_GAIN_PERCENT)
+}
+
+/// Compressing writer returned by `sampling_compressor`. Buffers the start
+/// of the stream until it knows which compression to use, then opens the
+/// inner writer for it.
+pub struct Sampler<W: Write, F: FnOnce(&Codec) -> errors::Result<W>> {
+    codec: Codec,
+    open: Option<F>,
+    sample: Vec<u8>,
+    inner: Option<BlockCompressor<W>>,
+}
+
+impl<W: Write, F: FnOnce(&Codec) -> errors::Result<W>> Sampler<W, F> {
+    fn start(&mut self) -> errors::Result<()> {
+        let mut codec = self.codec;
+        if codec.skip_incompressible && !worth_compressing(&codec, &self.sample)? {
+            debug!(
+                "{} saves less than {}% on a {} byte sample, storing uncompressed",
+                codec.compression.name(),
+                MIN_GAIN_PERCENT,
+                self.sample.len()
+            );
+            codec.compression = Compression::None;
+            codec.level = None;
+        }
+
+        let open = self.open.take().unwrap();
+        let mut inner = block_compressor(open(&codec)?, &codec);
+        inner.write_all(&self.sample)?;
+
+        self.sample = Vec::new();
+        self.inner = Some(inner);
+        Ok(())
+    }
+}
+
+impl<W: Write, F: FnOnce(&Codec) -> errors::Result<W>> Write for Sampler<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut inner) = self.inner {
+            return inner.write(buf);
+        }
+
+        let take = cmp::min(SAMPLE_SIZE - self.sample.len(), buf.len());
+        self.sample.extend_from_slice(&buf[..take]);
+
+        if self.sample.len() == SAMPLE_SIZE {
+            self.start()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        }
+
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner {
+            Some(ref mut inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Like `block_compressor`, but only opens the inner writer, through `open`,
+/// once the start of the stream has been sampled. `open` is handed the
+/// codec actually used, which stores the data uncompressed when `codec`
+/// skips incompressible data and the sample did not compress well.
+pub fn sampling_compressor<W, F>(codec: &Codec, open: F) -> Sampler<W, F>
+where
+    W: Write,
+    F: FnOnce(&Codec) -> errors::Result<W>,
+{
+    Sampler {
+        codec: *codec,
+        open: Some(open),
+        sample: Vec::with_capacity(SAMPLE_SIZE),
+        inner: None,
+    }
+}
+
+pub fn finish_sampler<W, F>(mut sampler: Sampler<W, F>) -> errors::Result<W>
+where
+    W: Write,
+    F: FnOnce(&Codec) -> errors::Result<W>,
+{
+    if sampler.inner.is_none() {
+        sampler.start()?;
+    }
+
+    finish_block_compressor(sampler.inner.unwrap())
+}
+
+/// Decompressing reader returned by `inflater`.
+pub enum Inflater<R: Read> {
+    Gzip(gzip::Decoder<R>),
+    GzipBlocks(gzip::MultiDecoder<R>),
+    Zstd(ZstdDecoder<io::BufReader<R>>),
+    Xz(XzDecoder<R>),
+    None(R),
+}
+
+impl<R: Read> Read for Inflater<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Inflater::Gzip(ref mut r) => r.read(buf),
+            Inflater::GzipBlocks(ref mut r) => r.read(buf),
+            Inflater::Zstd(ref mut r) => r.read(buf),
+            Inflater::Xz(ref mut r) => r.read(buf),
+            Inflater::None(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+/// Wraps `inner` in a decompressor for `compression`. `blocks` tells
+/// whether the data was compressed as independent blocks rather than a
+/// single stream.
+pub fn inflater<R: Read>(
+    inner: R,
+    compression: Compression,
+    blocks: bool,
+) -> errors::Result<Inflater<R>> {
+    let inflater = match (compression, blocks) {
+        (Compression::Gzip, false) => Inflater::Gzip(gzip::Decoder::new(inner)?),
+        (Compression::Gzip, true) => Inflater::GzipBlocks(gzip::MultiDecoder::new(inner)?),
+        // zstd reads concatenated frames either way.
+        (Compression::Zstd, _) => Inflater::Zstd(ZstdDecoder::new(inner)?),
+        (Compression::Xz, false) => Inflater::Xz(XzDecoder::new(inner)),
+        (Compression::Xz, true) => Inflater::Xz(XzDecoder::new_multi_decoder(inner)),
+        (Compression::None, _) => Inflater::None(inner),
+    };
+
+    Ok(inflater)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{self, RngCore};
+
+    #[test]
+    fn test_roundtrip() {
+        let data = "sneakercopy ".repeat(1000);
+        let codecs = [
+            Codec::default(),
+            Codec::new(Compression::Gzip, Some(0)).unwrap(),
+            Codec::new(Compression::Zstd, Some(19)).unwrap(),
+            Codec::new(Compression::Xz, None).unwrap(),
+            Codec::new(Compression::None, None).unwrap(),
+        ];
+
+        for codec in codecs.iter() {
+            let mut compressed = compressor(Vec::new(), codec).unwrap();
+            compressed.write_all(data.as_bytes()).unwrap();
+            let compressed = finish_compressor(compressed).unwrap();
+
+            let mut inflated = String::new();
+            inflater(compressed.as_slice(), codec.compression(), false)
+                .unwrap()
+                .read_to_string(&mut inflated)
+                .unwrap();
+            assert_eq!(data, inflated, "{:?}", codec);
+        }
+    }
+
+    fn sampled(codec: &Codec, data: &[u8]) -> (Compression, Vec<u8>) {
+        let mut used = None;
+        let mut sampler = sampling_compressor(codec, |codec: &Codec| {
+            used = Some(codec.compression());
+            Ok(Vec::new())
+        });
+        sampler.write_all(data).unwrap();
+        let compressed = finish_sampler(sampler).unwrap();
+
+        (used.unwrap(), compressed)
+    }
+
+    #[test]
+    fn test_skip_incompressible() {
+        let mut noise = vec![0; 2 * SAMPLE_SIZE];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let text = "sneakercopy ".repeat(SAMPLE_SIZE / 4).into_bytes();
+
+        let (used, compressed) = sampled(&Codec::default(), &noise);
+        assert_eq!(Compression::None, used);
+        assert_eq!(noise, compressed);
+
+        let (used, compressed) = sampled(&Codec::default(), &text);
+        assert_eq!(Compression::Gzip, used);
+        assert!(compressed.len() < text.len());
+
+        let mut codec = Codec::default();
+        codec.set_skip_incompressible(false);
+        assert_eq!(Compression::Gzip, sampled(&codec, &noise).0);
+    }
+
+    #[test]
+    fn test_block_size() {
+        // Blocks whatever the number of threads, so that it does not show
+        // in the output
+        let mut codec = Codec::default();
+        assert_eq!(Some(BLOCK_SIZE as u32), codec.block_size());
+        codec.set_threads(4);
+        assert_eq!(Some(BLOCK_SIZE as u32), codec.block_size());
+
+        let stored = Codec::new(Compression::None, None).unwrap();
+        assert_eq!(None, stored.block_size());
+    }
+
+    #[test]
+    fn test_blocks_deterministic() {
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE + 12345)
+            .map(|i| (i % 251) as u8 ^ (i / 4096) as u8)
+            .collect();
+
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Xz].iter() {
+            let mut outputs = Vec::new();
+            for threads in [1, 3].iter() {
+                let mut codec = Codec::new(*compression, None).unwrap();
+                codec.set_threads(*threads);
+
+                let (used, compressed) = sampled(&codec, &data);
+                assert_eq!(*compression, used);
+                outputs.push(compressed);
+            }
+            assert_eq!(outputs[0], outputs[1], "{:?}", compression);
+
+            let mut inflated = Vec::new();
+            inflater(outputs[0].as_slice(), *compression, true)
+                .unwrap()
+                .read_to_end(&mut inflated)
+                .unwrap();
+            assert!(data == inflated, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn test_levels() {
+        assert!(Codec::new(Compression::Gzip, Some(0)).is_ok());
+        assert!(Codec::new(Compression::Gzip, Some(9)).is_ok());
+        assert!(Codec::new(Compression::Gzip, Some(10)).is_err());
+        assert!(Codec::new(Compression::Zstd, Some(22)).is_ok());
+        assert!(Codec::new(Compression::Zstd, Some(23)).is_err());
+        assert!(Codec::new(Compression::Xz, Some(10)).is_err());
+        assert!(Codec::new(Compression::None, Some(1)).is_err());
+    }
 }
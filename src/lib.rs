@@ -4,6 +4,8 @@
 extern crate base64;
 #[macro_use]
 extern crate error_chain;
+extern crate globset;
+extern crate ignore;
 extern crate libflate;
 #[macro_use]
 extern crate log;
@@ -11,24 +13,34 @@ extern crate rand;
 extern crate sodiumoxide;
 extern crate spinners;
 extern crate tar;
+extern crate xz2;
+extern crate zstd;
 
 #[macro_use]
 mod builder;
 pub mod crypt;
 pub mod errors;
+pub mod filter;
 pub mod flate;
 pub mod pack;
 pub mod password;
+pub mod shamir;
 pub mod tarbox;
 
 use spinners::{Spinner, Spinners};
 use std::env;
 use std::ffi::OsStr;
+use std::fs;
 use std::fs::{DirBuilder, File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub type BufResult = errors::Result<Vec<u8>>;
+use errors::ResultExt;
+use tarbox::keyslot::KeySlot;
+use tarbox::secret::Factors;
 
 fn build_output_file_name(path: &PathBuf) -> PathBuf {
     let extension = path.extension().unwrap_or(OsStr::new(""));
@@ -58,17 +70,77 @@ fn build_output_path(input: &PathBuf, output: &Option<PathBuf>) -> PathBuf {
     output
 }
 
-/// Given a `path`, reads the resulting file or directory into a
-/// `tar` archive, encrypts the resulting archive, removes the unencrypted
-/// archive, and then compresses the encrypted archive, resulting in
-/// a "tarbox".
-pub fn seal_path(
-    path: &PathBuf,
-    output: &Option<PathBuf>,
-    force: bool,
-) -> errors::Result<tarbox::TarboxSecret> {
-    let target_path = build_output_path(path, output);
+/// Picks the path the tarbox is named after: the input itself when there
+/// is only one, otherwise the closest directory containing every input.
+fn output_name_source(inputs: &[PathBuf]) -> Option<PathBuf> {
+    if inputs.len() == 1 {
+        return Some(inputs[0].clone());
+    }
+
+    let mut parents = inputs.iter().filter_map(|p| p.parent());
+    let mut common = parents.next()?.to_path_buf();
+    for parent in parents {
+        while !parent.starts_with(&common) {
+            if !common.pop() {
+                return None;
+            }
+        }
+    }
+
+    if common.file_name().is_none() {
+        return None;
+    }
 
+    Some(common)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A spinner on stdout, or nothing when stdout carries data.
+struct Progress(Option<Spinner>);
+
+impl Progress {
+    fn new(show: bool) -> Progress {
+        if show {
+            Progress(Some(Spinner::new(Spinners::Dots12, "Prepping...".into())))
+        } else {
+            Progress(None)
+        }
+    }
+
+    fn message(&self, message: &str) {
+        if let Some(ref spinner) = self.0 {
+            spinner.message(message.into());
+        }
+    }
+
+    fn stop(self) {
+        if let Some(spinner) = self.0 {
+            spinner.stop();
+        }
+    }
+}
+
+/// Returns where the tarbox for `inputs` is written, given the `--output`
+/// option.
+pub fn output_path(inputs: &[PathBuf], output: &Option<PathBuf>) -> errors::Result<PathBuf> {
+    match output_name_source(inputs) {
+        Some(source) => Ok(build_output_path(&source, output)),
+        None => match *output {
+            Some(ref output) if !output.is_dir() => Ok(output.clone()),
+            _ => bail!(errors::ErrorKind::OutputPathRequired),
+        },
+    }
+}
+
+/// Creates the tarbox file at `path`. An existing file is only replaced
+/// when `force` is set.
+pub fn create_output(path: &PathBuf, force: bool) -> errors::Result<File> {
     let mut target_file = OpenOptions::new();
     target_file.create(true).write(true);
 
@@ -78,45 +150,232 @@ pub fn seal_path(
         target_file.create_new(true).truncate(false);
     }
 
-    let mut target_file = target_file.open(target_path)?;
+    Ok(target_file.open(path)?)
+}
+
+/// How a tarbox is encrypted and compressed.
+#[derive(Clone, Debug)]
+pub struct SealOptions {
+    pub kdf: tarbox::secret::Kdf,
+    pub codec: flate::Codec,
+    /// Number of secrets to generate, each of which opens the tarbox on
+    /// its own.
+    pub secrets: usize,
+    /// Recipients the tarbox is sealed for, alongside the secrets.
+    pub recipients: Vec<tarbox::recipient::PublicKey>,
+    /// Keyfile needed along with each secret. Without secrets, the keyfile
+    /// alone opens the tarbox.
+    pub keyfile: Option<tarbox::secret::Keyfile>,
+    /// Number of shares to split a further secret into, or 0 for none.
+    pub shares: usize,
+    /// Number of shares needed to recover that secret.
+    pub threshold: usize,
+}
+
+impl SealOptions {
+    fn check(&self) -> errors::Result<()> {
+        if self.secrets == 0
+            && self.shares == 0
+            && self.recipients.is_empty()
+            && self.keyfile.is_none()
+        {
+            bail!(errors::ErrorKind::NoKeySlots);
+        }
+
+        if self.shares > 0 {
+            shamir::check_threshold(self.threshold, self.shares)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for SealOptions {
+    fn default() -> SealOptions {
+        SealOptions {
+            kdf: tarbox::secret::Kdf::legacy(),
+            codec: flate::Codec::default(),
+            secrets: 1,
+            recipients: Vec::new(),
+            keyfile: None,
+            shares: 0,
+            threshold: 0,
+        }
+    }
+}
 
-    // Make a new `BoxSecret`
-    let password = password::generate_password();
-    let secret = tarbox::TarboxSecret::generate(password);
+/// What opens a newly sealed tarbox, to be handed out: the generated
+/// secrets and the shares of the shared secret, if any.
+#[derive(Clone, Debug, Default)]
+pub struct SealedSecrets {
+    pub secrets: Vec<tarbox::TarboxSecret>,
+    pub shares: Vec<shamir::Share>,
+}
 
-    let waiter = Spinner::new(Spinners::Dots12, "Prepping...".into());
+/// Seals whatever `pack` writes as a `tar` archive into a tarbox written to
+/// `dest`, returning `dest` and the newly generated secrets and shares.
+///
+/// The archive is encrypted with a random data key, which is wrapped in a
+/// key slot for each secret, the shared secret and each recipient, or for
+/// the keyfile alone when there are no secrets.
+///
+/// Each stage is a `Write` adapter around the next, so the archive is
+/// streamed through to `dest` instead of being assembled in memory.
+pub fn seal_archive<W, F>(
+    dest: W,
+    opts: &SealOptions,
+    original_name: Option<String>,
+    show_progress: bool,
+    pack: F,
+) -> errors::Result<(SealedSecrets, W)>
+where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> errors::Result<()>,
+{
+    opts.check()?;
 
-    // Pack the target files to the tar archive
-    debug!("packing path {:?} to archive buffer", path);
-    waiter.message("Packing...".into());
-    let buf = pack::pack_archive(&path)?;
+    let waiter = Progress::new(show_progress);
 
-    debug!("compressing buf of length {}", buf.len());
-    waiter.message("Compressing...".into());
-    let buf = flate::compress_buffer(&buf)?;
+    let key = tarbox::keyslot::gen_data_key();
+    let mut secrets = Vec::with_capacity(opts.secrets);
+    let mut slots = Vec::with_capacity(opts.secrets + opts.recipients.len() + 1);
+    for _ in 0..opts.secrets {
+        // Make a new `BoxSecret` for each slot
+        let password = password::generate_password();
+        let mut secret = tarbox::TarboxSecret::generate_with_kdf(password, opts.kdf);
+        if let Some(keyfile) = opts.keyfile {
+            secret.set_keyfile(keyfile);
+        }
+        slots.push(KeySlot::secret(&secret, &key)?);
+        secrets.push(secret);
+    }
+    let mut shares = Vec::new();
+    if opts.shares > 0 {
+        // The shared secret is never shown, only its shares
+        let (password, split) = shamir::generate(opts.threshold, opts.shares)?;
+        let mut secret = tarbox::TarboxSecret::generate_with_kdf(password, opts.kdf);
+        if let Some(keyfile) = opts.keyfile {
+            secret.set_keyfile(keyfile);
+        }
+        slots.push(KeySlot::secret(&secret, &key)?);
+        shares = split;
+    }
+    if let (0, 0, Some(keyfile)) = (opts.secrets, opts.shares, opts.keyfile) {
+        let mut secret = tarbox::TarboxSecret::generate_with_kdf(String::new(), opts.kdf);
+        secret.set_keyfile(keyfile);
+        slots.push(KeySlot::secret(&secret, &key)?);
+    }
+    for recipient in &opts.recipients {
+        slots.push(KeySlot::recipient(recipient, &key));
+    }
+    if !tarbox::keyslot::within_limits(&slots) {
+        bail!(errors::ErrorKind::TooManyKeySlots(slots.len()));
+    }
 
-    debug!("encrypting compressed buf (size {})", buf.len());
-    waiter.message("Encrypting...".into());
-    let buf = crypt::encrypt_buffer(&buf, &secret)?;
+    let mut attrs = tarbox::Attributes::with_key_slots(slots);
 
-    debug!("finalizing tarbox (size {})", buf.len());
-    waiter.message("Finishing up...".into());
-    let buf = tarbox::wrap_buffer(&buf, &secret)?;
+    attrs.set_created(unix_now());
+    if let Some(name) = original_name {
+        attrs.set_original_name(name);
+    }
+
+    // Build the pipeline from the output inwards:
+    // tar -> compression -> secretstream -> tarbox -> dest
+    // The header records the compression, so nothing is written until the
+    // start of the archive has been sampled.
+    let mut compressed = flate::sampling_compressor(&opts.codec, |codec: &flate::Codec| {
+        attrs.set_compression(codec.compression());
+        if let Some(block_size) = codec.block_size() {
+            attrs.set_compression_blocks(block_size);
+        }
+        let boxed = tarbox::Encoder::new(dest, attrs)?;
+        let header = boxed.authenticated_header()?;
+        crypt::Encryptor::with_key(boxed, &key, &header)
+    });
+
+    waiter.message("Packing...");
+    pack(&mut compressed)?;
+
+    waiter.message("Finishing up...");
+    let encrypted = flate::finish_sampler(compressed)?;
+    let boxed = encrypted.finish()?;
+    let mut dest = boxed.finish()?;
+    dest.flush()?;
 
-    target_file.write_all(&buf)?;
     waiter.stop();
 
-    Ok(secret)
+    let sealed = SealedSecrets {
+        secrets: secrets,
+        shares: shares,
+    };
+    Ok((sealed, dest))
 }
 
-pub fn unseal_path(
-    path: &PathBuf,
-    dest: &PathBuf,
-    sb: tarbox::TarboxSecretBuilder,
-) -> errors::Result<()> {
-    DirBuilder::new().recursive(true).create(&dest)?;
+/// Given one or more `paths`, reads the files and directories into a
+/// `tar` archive, compresses the archive, encrypts the compressed
+/// archive, and wraps it with a tarbox header, resulting in a "tarbox".
+pub fn seal_path(
+    paths: &[PathBuf],
+    output: &Option<PathBuf>,
+    force: bool,
+    seal_opts: &SealOptions,
+    opts: &pack::PackOptions,
+) -> errors::Result<SealedSecrets> {
+    seal_opts.check()?;
+    if paths.len() > 1 {
+        pack::entry_names(paths)?;
+    }
+
+    let target_path = output_path(paths, output)?;
+    let target_file = create_output(&target_path, force)?;
+
+    let original_name = if paths.len() == 1 {
+        paths[0].file_name().map(|n| n.to_string_lossy().into_owned())
+    } else {
+        None
+    };
+
+    let target_file = BufWriter::new(target_file);
+    let (sealed, _) = seal_archive(target_file, seal_opts, original_name, true, |dest| {
+        debug!("packing paths {:?} to archive stream", paths);
+        pack::pack_archive(paths, dest, opts)?;
+        Ok(())
+    })?;
+
+    Ok(sealed)
+}
+
+/// Header-level details of a tarbox, as reported by `inspect_path`.
+#[derive(Clone, Debug)]
+pub struct TarboxInfo {
+    pub magic: [u8; 2],
+    pub version: u8,
+    pub attributes: tarbox::Attributes,
+    pub header_len: u64,
+    pub payload_len: u64,
+}
+
+/// Reads only the header of the tarbox at `path`. No secret is needed and
+/// the payload is never decrypted.
+pub fn inspect_path(path: &PathBuf) -> errors::Result<TarboxInfo> {
+    let source_file = File::open(path)?;
+    let source_len = source_file.metadata()?.len();
 
-    let mut source_file = File::open(path)?;
+    let boxed = tarbox::Decoder::new(BufReader::new(source_file))?;
+    let header = boxed.header();
+    let header_len = header.len() as u64;
+
+    Ok(TarboxInfo {
+        magic: [header[0], header[1]],
+        version: header[2],
+        attributes: boxed.attributes().clone(),
+        header_len: header_len,
+        payload_len: source_len.saturating_sub(header_len),
+    })
+}
+
+fn open_file(path: &PathBuf) -> errors::Result<File> {
+    let source_file = File::open(path)?;
     let source_meta = source_file.metadata()?;
     debug!(
         "reading {} bytes from tarbox: {:?}",
@@ -124,44 +383,446 @@ pub fn unseal_path(
         path
     );
 
-    let waiter = Spinner::new(Spinners::Dots12, "Prepping...".into());
+    Ok(source_file)
+}
+
+/// A secret, a keyfile or both, to try on the secret key slots of a
+/// tarbox. A missing secret is asked for with `prompt`, but only once the
+/// header shows that one is needed.
+pub struct SecretUnlock {
+    pub password: Option<String>,
+    pub keyfile: Option<tarbox::secret::Keyfile>,
+    pub prompt: Option<Box<dyn Fn() -> String>>,
+}
+
+impl SecretUnlock {
+    pub fn password(password: String) -> SecretUnlock {
+        SecretUnlock {
+            password: Some(password),
+            keyfile: None,
+            prompt: None,
+        }
+    }
+
+    /// Checks that the slots opened by `factors` can be tried at all,
+    /// prompts for the secret unless the keyfile alone opens one of them,
+    /// and checks the secret for typos.
+    fn resolve(&mut self, factors: &[Factors]) -> errors::Result<()> {
+        let needs_keyfile = !factors.is_empty() && factors.iter().all(|f| f.needs_keyfile());
+        if self.keyfile.is_none() && needs_keyfile {
+            bail!(errors::ErrorKind::KeyfileRequired);
+        }
+
+        let keyfile_opens = self.keyfile.is_some() && factors.contains(&Factors::Keyfile);
+        if self.password.is_none() && factors.iter().any(|f| f.needs_secret()) && !keyfile_opens {
+            if let Some(ref prompt) = self.prompt {
+                self.password = Some(prompt());
+            }
+        }
+
+        // Typos are caught here rather than after a slow key derivation
+        if let Some(ref password) = self.password {
+            password::check_password(password)?;
+        }
+
+        Ok(())
+    }
+
+    /// Builds the secret for a slot that needs `factors`, or returns `None`
+    /// when a factor is missing.
+    fn secret_for(
+        &self,
+        factors: Factors,
+        kdf: tarbox::secret::Kdf,
+        salt: tarbox::secret::Salt,
+    ) -> errors::Result<Option<tarbox::TarboxSecret>> {
+        let password = match (factors.needs_secret(), self.password.as_ref()) {
+            (true, Some(password)) => password.clone(),
+            (true, None) => return Ok(None),
+            (false, _) => String::new(),
+        };
+        let keyfile = match (factors.needs_keyfile(), self.keyfile) {
+            (true, None) => return Ok(None),
+            (true, keyfile) => keyfile,
+            (false, _) => None,
+        };
+
+        let secret = tarbox::TarboxSecretBuilder::new()
+            .password(password)
+            .salt(salt)
+            .kdf(kdf)
+            .keyfile(keyfile)
+            .build()?;
+        Ok(Some(secret))
+    }
+}
+
+/// What opens a tarbox: one of the secrets it was sealed with, possibly
+/// along with a keyfile, or the identity of one of its recipients.
+pub enum Unlock {
+    Secret(SecretUnlock),
+    Identity(tarbox::recipient::Identity),
+}
+
+/// Tries `unlock` on each of the key slots of a tarbox in turn, returning
+/// the index of the first slot it opens and the data key from that slot.
+fn open_key_slots(
+    slots: &[KeySlot],
+    unlock: Unlock,
+) -> errors::Result<(usize, tarbox::keyslot::DataKey)> {
+    match unlock {
+        Unlock::Secret(su) => {
+            let mut tried = false;
+            for (i, slot) in slots.iter().enumerate() {
+                if let KeySlot::Secret {
+                    factors, kdf, salt, ..
+                } = *slot
+                {
+                    tried = true;
+                    let secret = match su.secret_for(factors, kdf, tarbox::secret::Salt(salt))? {
+                        Some(secret) => secret,
+                        None => continue,
+                    };
+                    if let Some(key) = slot.open_with_secret(&secret)? {
+                        return Ok((i, key));
+                    }
+                }
+            }
+
+            if !tried {
+                bail!(errors::ErrorKind::IdentityRequired);
+            }
+            bail!(errors::ErrorKind::WrongSecret)
+        }
+        Unlock::Identity(identity) => {
+            let mut tried = false;
+            for (i, slot) in slots.iter().enumerate() {
+                if let KeySlot::Recipient { .. } = *slot {
+                    tried = true;
+                    if let Some(key) = slot.open_with_identity(&identity) {
+                        return Ok((i, key));
+                    }
+                }
+            }
+
+            if !tried {
+                bail!(errors::ErrorKind::SecretRequired);
+            }
+            bail!(errors::ErrorKind::NotARecipient)
+        }
+    }
+}
+
+/// What a tarbox was unlocked with: the data key from one of its key slots
+/// or, for older tarboxes, the secret the key is derived from.
+enum TarboxKey {
+    Data(tarbox::keyslot::DataKey),
+    Secret(tarbox::TarboxSecret),
+}
+
+/// Reads a tarbox from `src` and returns a reader over the decrypted,
+/// inflated `tar` archive it contains, along with the spinner to report
+/// progress on.
+///
+/// Any prompt for the secret happens once the header has been read and
+/// before the spinner starts.
+fn open_tarbox<R: Read + 'static>(
+    src: R,
+    unlock: Unlock,
+    show_progress: bool,
+) -> errors::Result<(Box<dyn Read>, Progress)> {
+    let (boxed, key, waiter) = unlock_tarbox(src, unlock, show_progress)?;
+
+    Ok((decrypt_tarbox(boxed, &key)?, waiter))
+}
+
+/// Reads the header of a tarbox from `src` and finds the key that `unlock`
+/// opens it with, without reading any of the payload.
+fn unlock_tarbox<R: Read>(
+    src: R,
+    mut unlock: Unlock,
+    show_progress: bool,
+) -> errors::Result<(tarbox::Decoder<BufReader<R>>, TarboxKey, Progress)> {
+    let boxed = tarbox::Decoder::new(BufReader::new(src))?;
+    let attrs = boxed.attributes().clone();
+
+    if let Unlock::Secret(ref mut su) = unlock {
+        if attrs.key_slots().is_empty() {
+            // Keyfiles came with key slots; ignoring one would hide that
+            // it plays no part in opening the tarbox.
+            if su.keyfile.is_some() {
+                bail!(errors::ErrorKind::KeyfileUnsupported(attrs.version()));
+            }
+            su.resolve(&[Factors::Secret])?;
+        } else {
+            let factors: Vec<Factors> = attrs
+                .key_slots()
+                .iter()
+                .filter_map(KeySlot::factors)
+                .collect();
+            su.resolve(&factors)?;
+        }
+    }
+
+    let waiter = Progress::new(show_progress);
+    waiter.message("Decrypting...");
+    let key = if !attrs.key_slots().is_empty() {
+        let (_, key) = open_key_slots(attrs.key_slots(), unlock)?;
+        TarboxKey::Data(key)
+    } else {
+        // Older tarboxes derive the key straight from the secret and salt
+        let password = match unlock {
+            Unlock::Secret(SecretUnlock {
+                password: Some(password),
+                ..
+            }) => password,
+            Unlock::Secret(_) => bail!(errors::ErrorKind::WrongSecret),
+            Unlock::Identity(_) => bail!(errors::ErrorKind::SecretRequired),
+        };
 
-    let mut buf = Vec::new();
-    waiter.message("Reading file...".into());
-    source_file.read_to_end(&mut buf)?;
+        let secret = tarbox::TarboxSecretBuilder::new()
+            .password(password)
+            .salt(tarbox::secret::Salt(*attrs.salt().unwrap()))
+            .kdf(*attrs.kdf())
+            .build()?;
+        TarboxKey::Secret(secret)
+    };
 
-    debug!("unwrapping tarbox (size {})", buf.len());
-    waiter.message("Unwrapping...".into());
-    let (buf, attrs) = tarbox::unwrap_buffer(&buf)?;
+    Ok((boxed, key, waiter))
+}
 
-    let nonce = attrs.nonce();
-    let salt = attrs.salt();
-    let secret = sb
-        .nonce(tarbox::secret::Nonce::from_slice(nonce).unwrap())
-        .salt(tarbox::secret::Salt::from_slice(salt).unwrap())
-        .build()?;
+/// Returns a reader over the decrypted, inflated `tar` archive of `boxed`.
+fn decrypt_tarbox<'a, R: Read + 'a>(
+    boxed: tarbox::Decoder<R>,
+    key: &TarboxKey,
+) -> errors::Result<Box<dyn Read + 'a>> {
+    let attrs = boxed.attributes().clone();
+    let header = boxed.authenticated_header()?;
 
-    debug!("decrypting compressed buf (size {})", buf.len());
-    waiter.message("Decrypting...".into());
-    let buf = crypt::decrypt_buffer(&buf, &secret)?;
+    let decrypted = match *key {
+        TarboxKey::Data(ref key) => {
+            Box::new(crypt::Decryptor::with_key(boxed, key, Some(&header))?)
+        }
+        TarboxKey::Secret(ref secret) => crypt::decryptor(boxed, &attrs, secret, &header)?,
+    };
+
+    let inflated = flate::inflater(
+        decrypted,
+        attrs.compression(),
+        attrs.compression_blocks().is_some(),
+    )?;
+
+    Ok(Box::new(inflated))
+}
+
+/// Replaces the key slot of the tarbox at `path` that `unlock` opens with a
+/// slot for a newly generated secret, which is returned. The slot keeps the
+/// KDF and factors of the one it replaces but gets a fresh salt. Slots
+/// opened by a keyfile alone have no secret to replace and are left be.
+///
+/// Only the header is rewritten. A slot for the same factors and KDF has
+/// the same size, so the new header is normally written over the old one in
+/// place. Should its length differ, the whole payload is copied as is into
+/// a new file next to `path`, which then takes its place.
+pub fn rekey_path(
+    path: &PathBuf,
+    mut unlock: SecretUnlock,
+) -> errors::Result<tarbox::TarboxSecret> {
+    let mut boxed = tarbox::Decoder::new(BufReader::new(open_file(path)?))?;
+    let version = boxed.attributes().version();
+    if version < tarbox::attributes::VERSION_KEY_SLOTS {
+        bail!(errors::ErrorKind::RekeyUnsupported(version));
+    }
+
+    let mut slots = boxed.attributes().key_slots().to_vec();
+    let replaceable: Vec<usize> = (0..slots.len())
+        .filter(|&i| slots[i].factors().map_or(false, |f| f.needs_secret()))
+        .collect();
+    if replaceable.is_empty() {
+        bail!(errors::ErrorKind::NoSecretToReplace);
+    }
+
+    let candidates: Vec<KeySlot> = replaceable.iter().map(|&i| slots[i].clone()).collect();
+    let factors: Vec<Factors> = candidates.iter().filter_map(KeySlot::factors).collect();
+    unlock.resolve(&factors)?;
+    let keyfile = unlock.keyfile;
+
+    let header = boxed.header().to_vec();
+    let authenticated = boxed.authenticated_header()?;
+    let (index, key) = open_key_slots(&candidates, Unlock::Secret(unlock))?;
+    let index = replaceable[index];
+
+    // Make sure the slot really holds the key of this payload before
+    // handing out a secret for it.
+    crypt::Decryptor::with_key(&mut boxed, &key, Some(&authenticated))?;
+
+    let (factors, kdf) = match slots[index] {
+        KeySlot::Secret { factors, kdf, .. } => (factors, kdf),
+        KeySlot::Recipient { .. } => unreachable!(),
+    };
+    let mut new_secret =
+        tarbox::TarboxSecret::generate_with_kdf(password::generate_password(), kdf);
+    if let (true, Some(keyfile)) = (factors.needs_keyfile(), keyfile) {
+        new_secret.set_keyfile(keyfile);
+    }
+    slots[index] = KeySlot::secret(&new_secret, &key)?;
+    let new_header = tarbox::attributes::replace_key_slots(&header, &slots)?;
+
+    if new_header.len() == header.len() {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.write_all(&new_header)?;
+        file.sync_all()?;
+        return Ok(new_secret);
+    }
 
-    debug!("inflating buf of length {}", buf.len());
-    waiter.message("Inflating...".into());
-    let buf = flate::inflate_buffer(&buf)?;
+    // The temp file is removed on any error once it has been created
+    let mut file_name = path.file_name().unwrap().to_os_string();
+    file_name.push(".rekey");
+    let temp_path = path.with_file_name(file_name);
+    let temp_file = create_output(&temp_path, false)?;
+    let rewrite = || -> errors::Result<()> {
+        let mut source = open_file(path)?;
+        source.seek(io::SeekFrom::Start(header.len() as u64))?;
+        temp_file.set_permissions(source.metadata()?.permissions())?;
+
+        let mut dest = BufWriter::new(temp_file);
+        dest.write_all(&new_header)?;
+        io::copy(&mut BufReader::new(source), &mut dest)?;
+        dest.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    };
+
+    if let Err(e) = rewrite() {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    Ok(new_secret)
+}
+
+/// Unwraps, decrypts, and inflates the tarbox at `path`, unpacking the
+/// contained archive into `dest`. Returns how conflicts with existing
+/// files were resolved.
+pub fn unseal_path(
+    path: &PathBuf,
+    dest: &PathBuf,
+    unlock: Unlock,
+    opts: &pack::UnpackOptions,
+) -> errors::Result<pack::UnpackSummary> {
+    unseal_reader(open_file(path)?, dest, unlock, opts)
+}
+
+/// Like `unseal_path`, but reads the tarbox from `src`.
+pub fn unseal_reader<R: Read + 'static>(
+    src: R,
+    dest: &PathBuf,
+    unlock: Unlock,
+    opts: &pack::UnpackOptions,
+) -> errors::Result<pack::UnpackSummary> {
+    DirBuilder::new().recursive(true).create(&dest)?;
+
+    let (archive, waiter) = open_tarbox(src, unlock, true)?;
 
     debug!("unpacking archive to path: {:?}", dest);
-    waiter.message("Unpacking...".into());
-    pack::unpack_archive(&buf, &dest)?;
+    waiter.message("Unpacking...");
+    let summary = pack::unpack_archive(archive, &dest, opts)?;
 
     waiter.stop();
 
-    Ok(())
+    Ok(summary)
+}
+
+/// Reads a tarbox holding a single file from `src` and writes the file's
+/// contents to `dest`, returning `dest`.
+pub fn unseal_to_writer<R: Read + 'static, W: Write>(
+    src: R,
+    dest: W,
+    unlock: Unlock,
+) -> errors::Result<W> {
+    let (archive, _) = open_tarbox(src, unlock, false)?;
+
+    pack::unpack_single_file(archive, dest)
+}
+
+/// Unwraps, decrypts, and inflates the tarbox at `path` and writes the
+/// contents of the file `member` to `dest`, returning `dest`. Nothing is
+/// written to disk.
+pub fn cat_path<W: Write>(
+    path: &PathBuf,
+    member: &Path,
+    dest: W,
+    unlock: Unlock,
+) -> errors::Result<W> {
+    // The member is looked for first and copied on a second read, which
+    // reuses the key rather than unlocking the tarbox again.
+    let (boxed, key, _) = unlock_tarbox(open_file(path)?, unlock, false)?;
+    let mut first = Some(boxed);
+    let open_archive = || match first.take() {
+        Some(boxed) => decrypt_tarbox(boxed, &key),
+        None => {
+            let boxed = tarbox::Decoder::new(BufReader::new(open_file(path)?))?;
+            decrypt_tarbox(boxed, &key)
+        }
+    };
+
+    pack::cat_member(open_archive, member, dest)
+}
+
+/// Unwraps, decrypts, and inflates the tarbox at `path`, returning the
+/// metadata of every entry in the contained archive. Nothing is written
+/// to disk.
+pub fn list_path(
+    path: &PathBuf,
+    unlock: Unlock,
+) -> errors::Result<Vec<pack::EntryInfo>> {
+    let (archive, waiter) = open_tarbox(open_file(path)?, unlock, true)?;
+
+    waiter.message("Listing...");
+    let entries = pack::list_archive(archive)?;
+
+    waiter.stop();
+
+    Ok(entries)
+}
+
+/// Runs the tarbox at `path` through the whole unseal pipeline, reading
+/// every entry and discarding it. Problems found after the tarbox has been
+/// opened are reported as `ErrorKind::CorruptArchive`.
+pub fn verify_path(
+    path: &PathBuf,
+    unlock: Unlock,
+) -> errors::Result<pack::ArchiveStats> {
+    let (archive, waiter) = open_tarbox(open_file(path)?, unlock, true)?;
+
+    waiter.message("Verifying...");
+    let stats = pack::verify_archive(archive).chain_err(|| errors::ErrorKind::CorruptArchive)?;
+
+    waiter.stop();
+
+    Ok(stats)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{build_output_file_name, build_output_path};
+    use super::{
+        build_output_file_name, build_output_path, crypt, errors, flate, output_name_source,
+        pack, rekey_path, seal_archive, tarbox, unlock_tarbox, unseal_to_writer, SealOptions,
+        SealedSecrets, SecretUnlock, TarboxKey, Unlock,
+    };
+    use sodiumoxide::crypto::{secretbox, secretstream};
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+    use std::os::unix::fs::MetadataExt;
     use std::path::PathBuf;
+    use std::process;
+    use std::time::Instant;
+    use tarbox::attributes::{self, Compression};
+    use tarbox::keyslot::{self, KeySlot};
+    use tarbox::secret::{Factors, Kdf, KdfAlgorithm, KdfCost, NONCEBYTES, SALTBYTES};
 
     #[test]
     fn test_build_output_file_name() {
@@ -206,4 +867,210 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_output_name_source() {
+        // (inputs, expectation)
+        let results: [(&[&str], Option<&str>); 4] = [
+            (&["/tmp/test.txt"], Some("/tmp/test.txt")),
+            (&["/etc/nginx", "/etc/ssl/cert.pem"], Some("/etc")),
+            (&["/srv/app/README", "/srv/app/conf"], Some("/srv/app")),
+            (&["/etc/nginx", "/var/README"], None),
+        ];
+
+        for (inputs, result) in results.iter() {
+            let inputs: Vec<PathBuf> = inputs.iter().map(PathBuf::from).collect();
+            assert_eq!(result.map(PathBuf::from), output_name_source(&inputs));
+        }
+    }
+
+    #[test]
+    fn test_rekey() {
+        let path = env::temp_dir().join(format!("sneakercopy-{}-rekey.tarbox", process::id()));
+        let (SealedSecrets { secrets, .. }, sealed) =
+            seal_archive(Vec::new(), &SealOptions::default(), None, false, |dest| {
+                pack::pack_reader("a.txt", &b"hello"[..], dest)?;
+                Ok(())
+            }).unwrap();
+        fs::write(&path, &sealed).unwrap();
+
+        let unlock =
+            |secret: &tarbox::TarboxSecret| SecretUnlock::password(secret.password().clone());
+        let unseal = |secret: &tarbox::TarboxSecret| {
+            let src = File::open(&path).unwrap();
+            unseal_to_writer(src, Vec::new(), Unlock::Secret(unlock(secret)))
+        };
+
+        let inode = fs::metadata(&path).unwrap().ino();
+        let new_secret = rekey_path(&path, unlock(&secrets[0])).unwrap();
+        assert_eq!(b"hello".to_vec(), unseal(&new_secret).unwrap());
+        match unseal(&secrets[0]) {
+            Err(errors::Error(errors::ErrorKind::WrongSecret, _)) => (),
+            res => panic!(format!("expected `WrongSecret`, got: {:?}", res)),
+        }
+
+        // Only the key slot changed, in place
+        assert_eq!(inode, fs::metadata(&path).unwrap().ino());
+        let rekeyed = fs::read(&path).unwrap();
+        let header_len = tarbox::Decoder::new(sealed.as_slice()).unwrap().header().len();
+        assert_eq!(sealed.len(), rekeyed.len());
+        assert_eq!(&sealed[header_len..], &rekeyed[header_len..]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_gzip_level() {
+        // Random letters that only repeat every 1 KiB
+        let mut state = 1u32;
+        let letters: Vec<u8> = (0..1024)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b'a' + (state >> 16) as u8 % 26
+            }).collect();
+        let data: Vec<u8> = letters.iter().cycle().take(256 * 1024).cloned().collect();
+        let seal = |level| {
+            let opts = SealOptions {
+                codec: flate::Codec::new(Compression::Gzip, level).unwrap(),
+                ..SealOptions::default()
+            };
+            seal_archive(Vec::new(), &opts, None, false, |dest| {
+                pack::pack_reader("a.txt", data.as_slice(), dest)?;
+                Ok(())
+            }).unwrap()
+        };
+
+        let (SealedSecrets { secrets, .. }, sealed) = seal(Some(1));
+        let decoder = tarbox::Decoder::new(sealed.as_slice()).unwrap();
+        assert_eq!(Compression::Gzip, decoder.attributes().compression());
+
+        let unlock = SecretUnlock::password(secrets[0].password().clone());
+        let src = Cursor::new(sealed.clone());
+        let unsealed = unseal_to_writer(src, Vec::new(), Unlock::Secret(unlock)).unwrap();
+        assert_eq!(data, unsealed);
+
+        // The 128 byte window of level 1 misses the repeats
+        let (_, default) = seal(None);
+        assert!(sealed.len() > default.len());
+    }
+
+    #[test]
+    fn test_threads_deterministic() {
+        let data: Vec<u8> = (0..2 * flate::BLOCK_SIZE + 12345)
+            .map(|i| (i % 251) as u8 ^ (i / 4096) as u8)
+            .collect();
+
+        // Seals `data` on `threads` threads and returns the compressed
+        // stream, decrypted, since every seal has its own data key
+        let compressed = |threads| {
+            let mut opts = SealOptions::default();
+            opts.codec.set_threads(threads);
+            let (SealedSecrets { secrets, .. }, sealed) =
+                seal_archive(Vec::new(), &opts, None, false, |dest| {
+                    dest.write_all(&data)?;
+                    Ok(())
+                }).unwrap();
+
+            let unlock = SecretUnlock::password(secrets[0].password().clone());
+            let (boxed, key, _) =
+                unlock_tarbox(Cursor::new(sealed), Unlock::Secret(unlock), false).unwrap();
+            let blocks = boxed.attributes().compression_blocks();
+            let header = boxed.authenticated_header().unwrap();
+            let key = match key {
+                TarboxKey::Data(key) => key,
+                TarboxKey::Secret(_) => panic!("expected a data key"),
+            };
+
+            let mut compressed = Vec::new();
+            crypt::Decryptor::with_key(boxed, &key, Some(&header))
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+            (blocks, compressed)
+        };
+
+        let (blocks, single) = compressed(1);
+        assert_eq!(Some(flate::BLOCK_SIZE as u32), blocks);
+        assert!(compressed(4) == (blocks, single));
+    }
+
+    #[test]
+    fn test_slot_limits_before_kdf() {
+        // Each slot is affordable on its own, but not all of them together
+        let sensitive = Kdf::preset(KdfAlgorithm::Argon2id13, KdfCost::Sensitive).unwrap();
+        let slot = KeySlot::Secret {
+            factors: Factors::Secret,
+            kdf: sensitive,
+            salt: [0x2; SALTBYTES],
+            nonce: [0x3; NONCEBYTES],
+            wrapped: vec![0x4; secretstream::KEYBYTES + secretbox::MACBYTES],
+        };
+        let slots = vec![slot; (keyslot::MAX_KDF_COST / sensitive.cost()) as usize + 1];
+        let attrs = tarbox::Attributes::with_key_slots(slots);
+        let sealed = tarbox::Encoder::new(Vec::new(), attrs).unwrap().finish().unwrap();
+
+        // Trying the slots would take seconds each; parsing takes none
+        let started = Instant::now();
+        let unlock = Unlock::Secret(SecretUnlock::password(String::from("A-B-C")));
+        match unseal_to_writer(Cursor::new(sealed), Vec::new(), unlock) {
+            Err(errors::Error(
+                errors::ErrorKind::Tarbox(tarbox::errors::ErrorKind::InvalidAttribute(tag)),
+                _,
+            )) => assert_eq!(attributes::TAG_KEY_SLOTS, tag),
+            res => panic!(format!("expected `InvalidAttribute`, got: {:?}", res)),
+        }
+        assert!(started.elapsed().as_secs() < 1);
+    }
+
+    #[test]
+    fn test_keyfile_unlock() {
+        let path = env::temp_dir().join(format!("sneakercopy-{}-unlock-keyfile", process::id()));
+        fs::write(&path, b"keyfile contents").unwrap();
+        let keyfile = tarbox::secret::Keyfile::read_from(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let seal = |secrets: usize| {
+            let opts = SealOptions {
+                secrets: secrets,
+                keyfile: Some(keyfile),
+                ..SealOptions::default()
+            };
+            seal_archive(Vec::new(), &opts, None, false, |dest| {
+                pack::pack_reader("a.txt", &b"hello"[..], dest)?;
+                Ok(())
+            }).unwrap()
+        };
+        let unseal = |sealed: &Vec<u8>, password: Option<String>, keyfile| {
+            let unlock = SecretUnlock {
+                password: password,
+                keyfile: keyfile,
+                prompt: None,
+            };
+            unseal_to_writer(Cursor::new(sealed.clone()), Vec::new(), Unlock::Secret(unlock))
+        };
+
+        // A secret sealed along with a keyfile needs both
+        let (SealedSecrets { secrets, .. }, sealed) = seal(1);
+        let password = Some(secrets[0].password().clone());
+        assert_eq!(b"hello".to_vec(), unseal(&sealed, password.clone(), Some(keyfile)).unwrap());
+        match unseal(&sealed, password, None) {
+            Err(errors::Error(errors::ErrorKind::KeyfileRequired, _)) => (),
+            res => panic!(format!("expected `KeyfileRequired`, got: {:?}", res)),
+        }
+
+        // Without secrets, the keyfile alone opens it
+        let (keys, sealed) = seal(0);
+        assert!(keys.secrets.is_empty());
+        assert_eq!(b"hello".to_vec(), unseal(&sealed, None, Some(keyfile)).unwrap());
+
+        // Tarboxes from before key slots never take a keyfile
+        let attrs = tarbox::Attributes::new([0x5a; tarbox::secret::SALTBYTES]);
+        let legacy = tarbox::Encoder::new(Vec::new(), attrs).unwrap().finish().unwrap();
+        match unseal(&legacy, Some(String::from("A-B-C")), Some(keyfile)) {
+            Err(errors::Error(errors::ErrorKind::KeyfileUnsupported(version), _)) => {
+                assert_eq!(tarbox::attributes::VERSION_AUTHENTICATED, version)
+            }
+            res => panic!(format!("expected `KeyfileUnsupported`, got: {:?}", res)),
+        }
+    }
 }
@@ -1,28 +1,1435 @@
-use std::fs::File;
-use std::path::PathBuf;
-use std::vec::Vec;
+use ignore::WalkBuilder;
+use log::Level;
+use sodiumoxide::crypto::secretstream;
+use sodiumoxide::randombytes;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
 use tar;
+use tar::EntryType;
 
-use super::{errors, BufResult};
+use super::crypt;
+use super::errors;
+use super::filter::{EntryFilter, PathFilter};
 
-pub fn pack_archive(src: &PathBuf) -> BufResult {
-    let mut archive = tar::Builder::new(Vec::new());
-    let file_name = src.file_name().unwrap();
+/// How many symlinks `resolve_link` follows before giving up, like `ELOOP`.
+const MAX_LINKS: usize = 40;
 
-    if src.is_dir() {
-        debug!("recursively adding contents of {:?} to archive", file_name);
-        archive.append_dir_all(".", &src)?;
-    } else {
-        let mut src_file = File::open(src.clone())?;
-        archive.append_file(PathBuf::from(file_name), &mut src_file)?;
+/// Largest input `pack_reader` holds in memory rather than spooling to disk.
+pub const SPOOL_THRESHOLD: usize = 16 * 1024 * 1024;
+
+/// Controls which files under a directory end up in the archive.
+#[derive(Clone, Debug)]
+pub struct PackOptions {
+    pub filter: PathFilter,
+    /// Skip whatever `.gitignore` files found during the walk (and in
+    /// the directories above it) would have git ignore.
+    pub respect_gitignore: bool,
+}
+
+impl Default for PackOptions {
+    fn default() -> PackOptions {
+        PackOptions {
+            filter: PathFilter::empty(),
+            respect_gitignore: false,
+        }
+    }
+}
+
+/// Streams `srcs` into a `tar` archive written to `dest`, returning `dest`
+/// once the archive has been finished.
+///
+/// A single directory is archived by its contents, so that it unpacks in
+/// place. Otherwise every path becomes a top-level entry named after its
+/// basename, and two paths with the same basename are an error. `opts`
+/// is applied while walking directories; paths named in `srcs` are always
+/// packed.
+pub fn pack_archive<W: Write>(
+    srcs: &[PathBuf],
+    dest: W,
+    opts: &PackOptions,
+) -> errors::Result<W> {
+    let mut archive = tar::Builder::new(dest);
+
+    if srcs.len() == 1 && srcs[0].is_dir() {
+        debug!("recursively adding contents of {:?} to archive", srcs[0]);
+        append_tree(&mut archive, Path::new("."), &srcs[0], opts)?;
+        return archive.into_inner().map_err(|e| e.into());
+    }
+
+    let names = entry_names(srcs)?;
+    for (src, file_name) in srcs.iter().zip(names.iter()) {
+        if src.is_dir() {
+            debug!("recursively adding {:?} to archive as {:?}", src, file_name);
+            append_tree(&mut archive, file_name, src, opts)?;
+        } else {
+            debug!("adding {:?} to archive as {:?}", src, file_name);
+            let mut src_file = File::open(src)?;
+            archive.append_file(file_name, &mut src_file)?;
+        }
+    }
+
+    archive.into_inner().map_err(|e| e.into())
+}
+
+/// Adds the directory `src` and everything below it that passes `opts`
+/// to `archive`, under `name`. Entries are added in file name order, and
+/// `opts` sees their paths relative to the archive root.
+fn append_tree<W: Write>(
+    archive: &mut tar::Builder<W>,
+    name: &Path,
+    src: &Path,
+    opts: &PackOptions,
+) -> errors::Result<()> {
+    let root = src.to_path_buf();
+    let prefix = name.to_path_buf();
+    let filter = opts.filter.clone();
+
+    let mut walker = WalkBuilder::new(src);
+    walker
+        .standard_filters(false)
+        .follow_links(true)
+        .git_ignore(opts.respect_gitignore)
+        .parents(opts.respect_gitignore)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(move |entry| {
+            let rel_path = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+            if entry.depth() > 0 && filter.is_excluded(&filter_path(&prefix, rel_path)) {
+                debug!("excluding {:?}", entry.path());
+                return false;
+            }
+
+            true
+        });
+
+    // With include patterns, only matching files are added, along with the
+    // directories leading to them. Directories are held back until a file
+    // below them is added.
+    let include_dirs = !opts.filter.has_includes();
+    let mut pending_dirs: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+    // The walker prunes gitignored entries before `filter_entry` sees them,
+    // so they are found afterwards by listing the directories it walked.
+    let log_ignored = opts.respect_gitignore && log_enabled!(Level::Debug);
+    let mut walked = HashSet::new();
+    let mut walked_dirs = Vec::new();
+
+    for entry in walker.build() {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(src).unwrap_or(entry.path());
+        let archive_path = name.join(rel_path);
+        let filter_path = filter_path(name, rel_path);
+
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        if log_ignored {
+            walked.insert(entry.path().to_path_buf());
+            if is_dir {
+                walked_dirs.push(entry.path().to_path_buf());
+            }
+        }
+
+        if is_dir {
+            if include_dirs {
+                archive.append_dir(&archive_path, entry.path())?;
+            } else {
+                pending_dirs.retain(|(dir, _)| archive_path.starts_with(dir));
+                pending_dirs.push((archive_path, entry.path().to_path_buf()));
+            }
+            continue;
+        }
+
+        if !opts.filter.is_included(&filter_path) {
+            debug!("not included: {:?}", entry.path());
+            continue;
+        }
+
+        pending_dirs.retain(|(dir, _)| archive_path.starts_with(dir));
+        for (dir, dir_src) in pending_dirs.drain(..) {
+            archive.append_dir(&dir, &dir_src)?;
+        }
+        archive.append_path_with_name(entry.path(), &archive_path)?;
+    }
+
+    if log_ignored {
+        log_gitignored(name, src, &opts.filter, &walked_dirs, &walked);
     }
 
+    Ok(())
+}
+
+/// Logs the children of `walked_dirs` that the walk skipped without `filter`
+/// excluding them, which is to say the ones a `.gitignore` file pruned.
+fn log_gitignored(
+    name: &Path,
+    src: &Path,
+    filter: &PathFilter,
+    walked_dirs: &[PathBuf],
+    walked: &HashSet<PathBuf>,
+) {
+    for dir in walked_dirs {
+        let children = match fs::read_dir(dir) {
+            Ok(children) => children,
+            Err(_) => continue,
+        };
+
+        for child in children.filter_map(|c| c.ok()) {
+            let path = child.path();
+            if walked.contains(&path) {
+                continue;
+            }
+
+            let rel_path = path.strip_prefix(src).unwrap_or(&path);
+            if !filter.is_excluded(&filter_path(name, rel_path)) {
+                debug!("ignored by gitignore: {:?}", path);
+            }
+        }
+    }
+}
+
+/// The path filters see for `rel_path` below the tree archived as `name`:
+/// its path from the archive root, without a leading `.`.
+fn filter_path(name: &Path, rel_path: &Path) -> PathBuf {
+    name.join(rel_path)
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Writes a `tar` archive holding a single file named `name`, with the
+/// contents read from `src`, to `dest` and returns `dest`.
+///
+/// A `tar` header records the size of the data that follows it, so `src`
+/// has to be read to the end first. Up to `SPOOL_THRESHOLD` bytes are held
+/// in memory; anything larger is spooled to disk, see `spool`.
+pub fn pack_reader<R: Read, W: Write>(name: &str, src: R, dest: W) -> errors::Result<W> {
+    pack_spooled(name, src, dest, SPOOL_THRESHOLD)
+}
+
+fn pack_spooled<R: Read, W: Write>(
+    name: &str,
+    mut src: R,
+    dest: W,
+    threshold: usize,
+) -> errors::Result<W> {
+    let mut head = Vec::new();
+    src.by_ref()
+        .take(threshold as u64 + 1)
+        .read_to_end(&mut head)?;
+
+    let (size, mut data): (u64, Box<dyn Read>) = if head.len() <= threshold {
+        (head.len() as u64, Box::new(io::Cursor::new(head)))
+    } else {
+        spool(head, src)?
+    };
+
+    let mtime = UNIX_EPOCH.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o600);
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_entry_type(EntryType::Regular);
+
+    let mut archive = tar::Builder::new(dest);
+    archive.append_data(&mut header, name, &mut data)?;
+
     archive.into_inner().map_err(|e| e.into())
 }
 
-pub fn unpack_archive(buf: &Vec<u8>, dest: &PathBuf) -> errors::Result<()> {
-    let mut archive = tar::Archive::new(buf.as_slice());
-    archive.unpack(&dest)?;
+/// Writes `head` and the rest of `src` to a temporary file, returning the
+/// size of the data and a reader over it.
+///
+/// The file is unlinked as soon as it is created, and what goes into it is
+/// encrypted with a key that only lives in memory, so no plaintext is left
+/// behind on disk.
+fn spool<R: Read>(head: Vec<u8>, mut src: R) -> errors::Result<(u64, Box<dyn Read>)> {
+    let path = env::temp_dir().join(format!(
+        "sneakercopy-{}-{:08x}.spool",
+        process::id(),
+        randombytes::randombytes_uniform(u32::max_value())
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    debug!("spooling input to {:?}", path);
+
+    let key = secretstream::gen_key();
+    let mut spooled = crypt::Encryptor::with_key(BufWriter::new(file), &key, &[])?;
+    spooled.write_all(&head)?;
+    let size = head.len() as u64 + io::copy(&mut src, &mut spooled)?;
+
+    let mut file = spooled.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    let data = crypt::Decryptor::with_key(BufReader::new(file), &key, Some(&[]))?;
+
+    Ok((size, Box::new(data)))
+}
+
+/// Returns the top-level entry name each of `srcs` is archived under,
+/// failing if a path has no basename or two paths share one.
+pub fn entry_names(srcs: &[PathBuf]) -> errors::Result<Vec<PathBuf>> {
+    let mut names = Vec::with_capacity(srcs.len());
+    for src in srcs {
+        let file_name = match src.file_name() {
+            Some(name) => PathBuf::from(name),
+            None => bail!(errors::ErrorKind::NoEntryName(
+                src.to_string_lossy().into_owned()
+            )),
+        };
+
+        if names.contains(&file_name) {
+            bail!(errors::ErrorKind::DuplicateEntryName(
+                file_name.to_string_lossy().into_owned()
+            ));
+        }
+        names.push(file_name);
+    }
+
+    Ok(names)
+}
+
+/// What to do when an archive entry would replace a file that already
+/// exists in the destination. Existing directories are always merged into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Fail before writing the entry.
+    Error,
+    /// Leave the existing file alone.
+    Skip,
+    /// Replace the existing file.
+    Overwrite,
+    /// Write the entry next to the existing file, as `name.1`, `name.2`...
+    Rename,
+    /// Replace the existing file only if the entry was modified later.
+    Newer,
+}
+
+impl ConflictPolicy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ConflictPolicy::Error => "error",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::Rename => "rename",
+            ConflictPolicy::Newer => "newer",
+        }
+    }
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> ConflictPolicy {
+        ConflictPolicy::Error
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(ConflictPolicy::Error),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "rename" => Ok(ConflictPolicy::Rename),
+            "newer" => Ok(ConflictPolicy::Newer),
+            _ => Err(format!("unknown conflict policy: {}", s)),
+        }
+    }
+}
+
+/// Controls how an archive is unpacked.
+#[derive(Default)]
+pub struct UnpackOptions {
+    /// Skip character and block device entries instead of failing.
+    pub skip_devices: bool,
+    pub on_conflict: ConflictPolicy,
+    /// Only entries whose path this returns `true` for are unpacked. It is
+    /// given the entry path before any components are stripped.
+    pub filter: Option<EntryFilter>,
+    /// Leading path components removed from every entry, like
+    /// `tar --strip-components`. Entries with no path left are skipped.
+    pub strip_components: usize,
+}
+
+impl fmt::Debug for UnpackOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnpackOptions")
+            .field("skip_devices", &self.skip_devices)
+            .field("on_conflict", &self.on_conflict)
+            .field("filter", &self.filter.is_some())
+            .field("strip_components", &self.strip_components)
+            .finish()
+    }
+}
+
+/// The conflicts `unpack_archive` resolved, by archive path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnpackSummary {
+    pub skipped: Vec<PathBuf>,
+    pub overwritten: Vec<PathBuf>,
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Unpacks the `tar` archive read from `src` into `dest`, which must exist.
+///
+/// Each entry is checked right before it is written, against `dest` as the
+/// entries before it left it: paths must be relative and free of `..`,
+/// links must resolve inside `dest` once the symlinks on disk are followed,
+/// nothing may be written through a symlink that leads out of `dest`, and
+/// device nodes are refused unless `opts.skip_devices` is set. Entries that
+/// collide with existing files are handled according to `opts.on_conflict`.
+///
+/// The archive is streamed, so an entry that fails a check stops the unpack
+/// with the entries before it already written.
+pub fn unpack_archive<R: Read>(
+    src: R,
+    dest: &PathBuf,
+    opts: &UnpackOptions,
+) -> errors::Result<UnpackSummary> {
+    let root = dest.canonicalize()?;
+    let mut archive = tar::Archive::new(src);
+    let mut summary = UnpackSummary::default();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let raw_path = entry.path()?.into_owned();
+        let entry_type = entry.header().entry_type();
+
+        let path = match relative_path(&raw_path) {
+            Some(path) => path,
+            None => bail!(errors::ErrorKind::UnsafeEntryPath(display(&raw_path))),
+        };
+
+        if let Some(ref filter) = opts.filter {
+            if !filter(&path) {
+                continue;
+            }
+        }
+
+        let path = strip_components(&path, opts.strip_components);
+        if path.as_os_str().is_empty() && opts.strip_components > 0 {
+            debug!("nothing left of {:?} after stripping", raw_path);
+            continue;
+        }
+
+        if entry_type.is_character_special() || entry_type.is_block_special() {
+            if opts.skip_devices {
+                debug!("skipping device entry {:?}", raw_path);
+                continue;
+            }
+            bail!(errors::ErrorKind::DeviceEntry(display(&raw_path)));
+        }
+
+        let mut hard_link_target = None;
+        if let Some(target) = entry.link_name()? {
+            let resolved = if entry_type.is_symlink() {
+                // Symlink targets are relative to the link's own directory.
+                let parent = path.parent().unwrap_or_else(|| Path::new(""));
+                resolve_link(&root, parent, &target)
+            } else {
+                // Hard link targets name another entry of the archive, so
+                // they lose the same leading components.
+                hard_link_target = relative_path(&target)
+                    .map(|target| strip_components(&target, opts.strip_components));
+                hard_link_target.clone()
+            };
+
+            let inside = match resolved {
+                Some(ref resolved) => stays_inside(&root, &root.join(resolved)),
+                None => false,
+            };
+            if !inside {
+                bail!(errors::ErrorKind::LinkEscape(
+                    display(&raw_path),
+                    display(&target)
+                ));
+            }
+        }
+
+        // An empty path is the archive root, which is `dest` itself.
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        // Existing directories are reused rather than replaced, so for them
+        // the entry itself must not lead elsewhere either.
+        let checked = if entry_type.is_dir() {
+            Some(path.as_path())
+        } else {
+            path.parent()
+        };
+        if let Some(checked) = checked {
+            if !stays_inside(&root, &root.join(checked)) {
+                bail!(errors::ErrorKind::UnsafeEntryPath(display(&raw_path)));
+            }
+        }
+
+        let target = root.join(&path);
+        let existing = match fs::symlink_metadata(&target) {
+            Ok(meta) => meta,
+            Err(_) => {
+                unpack_entry(&mut entry, &root, &path, &hard_link_target)?;
+                continue;
+            }
+        };
+
+        if entry_type.is_dir() {
+            if !existing.is_dir() {
+                bail!(errors::ErrorKind::FileExists(display(&path)));
+            }
+            unpack_entry(&mut entry, &root, &path, &hard_link_target)?;
+            continue;
+        }
+
+        match opts.on_conflict {
+            ConflictPolicy::Skip => {
+                debug!("skipping existing {:?}", target);
+                summary.skipped.push(path);
+            }
+            ConflictPolicy::Newer if !is_newer(entry.header(), &existing)? => {
+                debug!("keeping newer {:?}", target);
+                summary.skipped.push(path);
+            }
+            ConflictPolicy::Overwrite | ConflictPolicy::Newer => {
+                if existing.is_dir() {
+                    bail!(errors::ErrorKind::FileExists(display(&path)));
+                }
+
+                debug!("overwriting {:?}", target);
+                fs::remove_file(&target)?;
+                unpack_entry(&mut entry, &root, &path, &hard_link_target)?;
+                summary.overwritten.push(path);
+            }
+            ConflictPolicy::Rename => {
+                let renamed = free_name(&root, &path);
+                debug!("unpacking {:?} as {:?}", path, renamed);
+                unpack_entry(&mut entry, &root, &renamed, &hard_link_target)?;
+                summary.renamed.push((path, renamed));
+            }
+            ConflictPolicy::Error => bail!(errors::ErrorKind::FileExists(display(&path))),
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Whether the entry with `header` was modified after the `existing` file.
+fn is_newer(header: &tar::Header, existing: &fs::Metadata) -> errors::Result<bool> {
+    let existing_mtime = existing
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    Ok(header.mtime()? > existing_mtime)
+}
+
+/// Writes `entry` to `path` under `root`. Hard links are made to
+/// `hard_link_target` rather than to the link name stored in the entry, so
+/// that stripped components are accounted for.
+fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    root: &Path,
+    path: &Path,
+    hard_link_target: &Option<PathBuf>,
+) -> errors::Result<()> {
+    let target = root.join(path);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match *hard_link_target {
+        Some(ref link_target) => fs::hard_link(root.join(link_target), &target)?,
+        None => {
+            entry.unpack(&target)?;
+        }
+    }
 
     Ok(())
 }
+
+/// Removes the first `n` components of `path`.
+fn strip_components(path: &Path, n: usize) -> PathBuf {
+    path.components().skip(n).collect()
+}
+
+/// Copies the contents of the only file in the `tar` archive read from
+/// `src` to `dest`, returning `dest`. Directory entries are ignored, while
+/// links and other special entries are an error. Fails if the archive holds
+/// no file or more than one, although in the latter case the first file has
+/// already been written.
+pub fn unpack_single_file<R: Read, W: Write>(src: R, mut dest: W) -> errors::Result<W> {
+    let mut archive = tar::Archive::new(src);
+    let mut found = false;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            continue;
+        }
+
+        if !is_file(entry_type) {
+            bail!(errors::ErrorKind::MemberNotFile(display(&entry.path()?)));
+        }
+        if found {
+            bail!(errors::ErrorKind::NotSingleFile);
+        }
+
+        io::copy(&mut entry, &mut dest)?;
+        found = true;
+    }
+
+    if !found {
+        bail!(errors::ErrorKind::NotSingleFile);
+    }
+
+    // Read what is left of the stream so that it is authenticated up to
+    // its final chunk.
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    dest.flush()?;
+
+    Ok(dest)
+}
+
+/// Writes the contents of the archive file `member` to `dest`, returning
+/// `dest`. The archive is read twice, each time from a fresh reader
+/// returned by `open`: first to find the entry, then to copy it.
+///
+/// As when unpacking over existing files, the last entry named `member`
+/// wins. If it is a hard link, the file it links to is written instead.
+pub fn cat_member<R, F, W>(mut open: F, member: &Path, dest: W) -> errors::Result<W>
+where
+    R: Read,
+    F: FnMut() -> errors::Result<R>,
+    W: Write,
+{
+    let wanted = match relative_path(member) {
+        Some(ref path) if !path.as_os_str().is_empty() => path.clone(),
+        _ => bail!(errors::ErrorKind::MemberNotFound(display(member))),
+    };
+
+    let index = find_member(open()?, &wanted)?;
+    copy_entry(open()?, index, dest)
+}
+
+/// Returns the position in the archive of the entry that holds the
+/// contents of `wanted`, following hard links. The whole archive is read,
+/// so that it is authenticated before anything is written.
+fn find_member<R: Read>(src: R, wanted: &Path) -> errors::Result<usize> {
+    let mut archive = tar::Archive::new(src);
+    let mut entries = Vec::new();
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let link = match entry.link_name()? {
+            Some(ref target) => relative_path(target),
+            None => None,
+        };
+        entries.push((
+            relative_path(&entry.path()?),
+            entry.header().entry_type(),
+            link,
+        ));
+    }
+
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+
+    // A hard link refers to whichever entry of that name came before it
+    let mut wanted = wanted.to_path_buf();
+    let mut before = entries.len();
+    loop {
+        let index = match entries[..before]
+            .iter()
+            .rposition(|e| e.0.as_ref() == Some(&wanted))
+        {
+            Some(index) => index,
+            None => bail!(errors::ErrorKind::MemberNotFound(display(&wanted))),
+        };
+
+        match entries[index] {
+            (_, EntryType::Link, Some(ref target)) => {
+                wanted = target.clone();
+                before = index;
+            }
+            (_, entry_type, _) if is_file(entry_type) => return Ok(index),
+            _ => bail!(errors::ErrorKind::MemberNotFile(display(&wanted))),
+        }
+    }
+}
+
+/// Writes the contents of the entry at `index` of the archive read from
+/// `src` to `dest`, returning `dest`.
+fn copy_entry<R: Read, W: Write>(src: R, index: usize, mut dest: W) -> errors::Result<W> {
+    let mut archive = tar::Archive::new(src);
+    for (i, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        if i == index {
+            io::copy(&mut entry, &mut dest)?;
+        }
+    }
+
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    dest.flush()?;
+
+    Ok(dest)
+}
+
+/// Finds the first of `path.1`, `path.2`... that does not exist under `root`.
+fn free_name(root: &Path, path: &Path) -> PathBuf {
+    let mut n = 1;
+    loop {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+
+        let candidate = PathBuf::from(name);
+        if fs::symlink_metadata(root.join(&candidate)).is_err() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Normalizes an archive path, dropping `.` components. Returns `None` for
+/// absolute paths and paths containing `..`.
+fn relative_path(path: &Path) -> Option<PathBuf> {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normal.push(part),
+            Component::CurDir => continue,
+            Component::ParentDir | Component::RootDir | Component::Prefix(..) => return None,
+        }
+    }
+
+    Some(normal)
+}
+
+/// Resolves the symlink `target` of an entry in the archive directory
+/// `dir`, starting from `root` and following the symlinks already on disk
+/// the way the link itself will be followed. Returns `None` for absolute
+/// targets, symlink loops, and targets that climb out of a directory that
+/// does not exist yet, since a later entry could make it a symlink.
+fn resolve_link(root: &Path, dir: &Path, target: &Path) -> Option<PathBuf> {
+    if target.has_root() {
+        return None;
+    }
+
+    // Components still to resolve, the next one last
+    let mut rest = reversed_components(target);
+    rest.extend(reversed_components(dir));
+
+    let mut resolved = root.to_path_buf();
+    let mut on_disk = true;
+    let mut links = 0;
+    while let Some(part) = rest.pop() {
+        match part.components().next() {
+            Some(Component::Normal(name)) => {
+                resolved.push(name);
+                if !on_disk {
+                    continue;
+                }
+
+                match fs::symlink_metadata(&resolved) {
+                    Ok(ref meta) if meta.file_type().is_symlink() => {
+                        links += 1;
+                        if links > MAX_LINKS {
+                            return None;
+                        }
+                        rest.extend(reversed_components(&fs::read_link(&resolved).ok()?));
+                        resolved.pop();
+                    }
+                    Ok(_) => (),
+                    Err(_) => on_disk = false,
+                }
+            }
+            Some(Component::ParentDir) => {
+                if !on_disk {
+                    return None;
+                }
+                resolved.pop();
+            }
+            // Only symlinks already on disk can lead here
+            Some(Component::RootDir) => resolved = PathBuf::from("/"),
+            Some(Component::CurDir) | None => continue,
+            Some(Component::Prefix(..)) => return None,
+        }
+    }
+
+    Some(resolved)
+}
+
+fn reversed_components(path: &Path) -> Vec<PathBuf> {
+    path.components()
+        .rev()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect()
+}
+
+/// Whether `path` is still inside `root` once the symlinks already on disk
+/// are followed. Only the part of `path` that exists is resolved.
+fn stays_inside(root: &Path, path: &Path) -> bool {
+    let mut existing = path;
+    loop {
+        if existing.symlink_metadata().is_ok() {
+            break;
+        }
+
+        existing = match existing.parent() {
+            Some(parent) => parent,
+            None => return false,
+        };
+    }
+
+    match existing.canonicalize() {
+        Ok(resolved) => resolved.starts_with(root),
+        Err(_) => false,
+    }
+}
+
+/// Whether entries of `entry_type` hold the contents of a regular file.
+fn is_file(entry_type: EntryType) -> bool {
+    entry_type.is_file() || entry_type.is_contiguous()
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Metadata of a single archive entry, as reported by `list_archive`.
+#[derive(Clone, Debug)]
+pub struct EntryInfo {
+    pub path: PathBuf,
+    pub link_name: Option<PathBuf>,
+    pub entry_type: EntryType,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+    pub owner: String,
+}
+
+impl EntryInfo {
+    /// A short, stable name for the entry type.
+    pub fn type_name(&self) -> &'static str {
+        match self.entry_type {
+            EntryType::Regular | EntryType::Continuous => "file",
+            EntryType::Directory => "dir",
+            EntryType::Symlink => "symlink",
+            EntryType::Link => "hardlink",
+            EntryType::Char => "char",
+            EntryType::Block => "block",
+            EntryType::Fifo => "fifo",
+            _ => "other",
+        }
+    }
+
+    /// The entry type and permission bits in `ls -l` form, e.g. `drwxr-xr-x`.
+    pub fn permissions(&self) -> String {
+        let type_char = match self.entry_type {
+            EntryType::Directory => 'd',
+            EntryType::Symlink => 'l',
+            EntryType::Link => 'h',
+            EntryType::Char => 'c',
+            EntryType::Block => 'b',
+            EntryType::Fifo => 'p',
+            _ => '-',
+        };
+
+        let mut perms = String::with_capacity(10);
+        perms.push(type_char);
+        for shift in [6, 3, 0].iter() {
+            let bits = (self.mode >> shift) & 0o7;
+            perms.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            perms.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            perms.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+        }
+
+        perms
+    }
+
+    /// The modification time as `YYYY-MM-DD HH:MM` in UTC.
+    pub fn formatted_mtime(&self) -> String {
+        format_timestamp(self.mtime)
+    }
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM` in UTC.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = timestamp / 86400;
+    let secs = timestamp % 86400;
+
+    // Civil date from days since the epoch, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+/// Walks every entry of the `tar` archive read from `src` and returns
+/// its metadata without extracting anything.
+pub fn list_archive<R: Read>(src: R) -> errors::Result<Vec<EntryInfo>> {
+    let mut archive = tar::Archive::new(src);
+    let mut entries = Vec::new();
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+
+        let owner = match (header.username(), header.groupname()) {
+            (Ok(Some(user)), Ok(Some(group))) if !user.is_empty() => {
+                format!("{}/{}", user, group)
+            }
+            _ => format!("{}/{}", header.uid()?, header.gid()?),
+        };
+
+        entries.push(EntryInfo {
+            path: entry.path()?.into_owned(),
+            link_name: entry.link_name()?.map(|l| l.into_owned()),
+            entry_type: header.entry_type(),
+            size: header.size()?,
+            mode: header.mode()? & 0o7777,
+            mtime: header.mtime()?,
+            owner: owner,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Totals gathered while verifying an archive.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArchiveStats {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Walks every entry of the `tar` archive read from `src`, reading and
+/// discarding its data, then drains `src` so that the whole stream is
+/// checked, not just the part the archive occupies.
+pub fn verify_archive<R: Read>(src: R) -> errors::Result<ArchiveStats> {
+    let mut archive = tar::Archive::new(src);
+    let mut stats = ArchiveStats::default();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        debug!("verifying entry {:?}", entry.path()?);
+
+        stats.bytes += io::copy(&mut entry, &mut io::sink())?;
+        stats.entries += 1;
+    }
+
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    fn temp_dest(name: &str) -> PathBuf {
+        let dest = env::temp_dir().join(format!("sneakercopy-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(&dest).unwrap();
+        dest
+    }
+
+    /// Appends an entry with a raw, unvalidated path, as a hostile archive
+    /// would contain.
+    fn append_raw<W: Write>(
+        archive: &mut tar::Builder<W>,
+        path: &str,
+        entry_type: EntryType,
+        link_name: Option<&str>,
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_entry_type(entry_type);
+        if let Some(link_name) = link_name {
+            header.set_link_name(link_name).unwrap();
+        }
+        header.set_cksum();
+        archive.append(&header, io::empty()).unwrap();
+    }
+
+    fn unpack_error(entries: &[(&str, EntryType, Option<&str>)], dest: &PathBuf) -> errors::Error {
+        let mut archive = tar::Builder::new(Vec::new());
+        for (path, entry_type, link_name) in entries.iter() {
+            append_raw(&mut archive, path, *entry_type, *link_name);
+        }
+        let buf = archive.into_inner().unwrap();
+
+        unpack_archive(buf.as_slice(), dest, &UnpackOptions::default()).unwrap_err()
+    }
+
+    fn make_archive() -> Vec<u8> {
+        let mut archive = tar::Builder::new(Vec::new());
+        append_conf(&mut archive);
+        archive.into_inner().unwrap()
+    }
+
+    fn append_conf<W: Write>(archive: &mut tar::Builder<W>) {
+        let mut header = tar::Header::new_gnu();
+        header.set_path("configs/app.conf").unwrap();
+        header.set_size(5);
+        header.set_mode(0o640);
+        header.set_mtime(1_534_000_000);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        header.set_entry_type(EntryType::Regular);
+        header.set_cksum();
+        archive.append(&header, "hello".as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_list_archive() {
+        let buf = make_archive();
+        let entries = list_archive(buf.as_slice()).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.path, PathBuf::from("configs/app.conf"));
+        assert_eq!(entry.size, 5);
+        assert_eq!(entry.owner, "1000/1000");
+        assert_eq!(entry.type_name(), "file");
+        assert_eq!(entry.permissions(), "-rw-r-----");
+        assert_eq!(entry.formatted_mtime(), "2018-08-11 15:06");
+    }
+
+    #[test]
+    fn test_unpack_archive() {
+        let dest = temp_dest("unpack");
+        let mut archive = tar::Builder::new(Vec::new());
+        append_conf(&mut archive);
+        append_raw(&mut archive, "app.conf", EntryType::Symlink, Some("configs/app.conf"));
+        append_raw(&mut archive, "./configs/copy.conf", EntryType::Link, Some("configs/app.conf"));
+        let buf = archive.into_inner().unwrap();
+
+        unpack_archive(buf.as_slice(), &dest, &UnpackOptions::default()).unwrap();
+        assert_eq!(fs::read(dest.join("app.conf")).unwrap(), b"hello");
+        assert_eq!(fs::read(dest.join("configs/copy.conf")).unwrap(), b"hello");
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_selected() {
+        let dest = temp_dest("selected");
+        let mut archive = tar::Builder::new(Vec::new());
+        append_conf(&mut archive);
+        append_raw(&mut archive, "configs/other.conf", EntryType::Regular, None);
+        append_raw(&mut archive, "configs/copy.conf", EntryType::Link, Some("configs/app.conf"));
+        let buf = archive.into_inner().unwrap();
+
+        let opts = UnpackOptions {
+            filter: Some(Box::new(|path: &Path| path != Path::new("configs/other.conf"))),
+            strip_components: 1,
+            ..Default::default()
+        };
+        unpack_archive(buf.as_slice(), &dest, &opts).unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(&dest)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["app.conf", "copy.conf"]);
+        assert_eq!(fs::read(dest.join("copy.conf")).unwrap(), b"hello");
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_conflicts() {
+        let dest = temp_dest("conflicts");
+        let conf = dest.join("configs/app.conf");
+        let mut archive = tar::Builder::new(Vec::new());
+        append_conf(&mut archive);
+        let buf = archive.into_inner().unwrap();
+
+        let unpack = |policy| {
+            fs::create_dir_all(conf.parent().unwrap()).unwrap();
+            fs::write(&conf, b"local").unwrap();
+
+            let opts = UnpackOptions {
+                on_conflict: policy,
+                ..Default::default()
+            };
+            unpack_archive(buf.as_slice(), &dest, &opts)
+        };
+
+        match unpack(ConflictPolicy::Error) {
+            Err(errors::Error(errors::ErrorKind::FileExists(_), _)) => (),
+            res => panic!("expected a conflict, got {:?}", res),
+        }
+
+        // The local file was modified after the archived one.
+        for policy in [ConflictPolicy::Skip, ConflictPolicy::Newer].iter() {
+            let summary = unpack(*policy).unwrap();
+            assert_eq!(summary.skipped, [PathBuf::from("configs/app.conf")]);
+            assert_eq!(fs::read(&conf).unwrap(), b"local");
+        }
+
+        let summary = unpack(ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(summary.overwritten, [PathBuf::from("configs/app.conf")]);
+        assert_eq!(fs::read(&conf).unwrap(), b"hello");
+
+        let summary = unpack(ConflictPolicy::Rename).unwrap();
+        let renamed = PathBuf::from("configs/app.conf.1");
+        assert_eq!(
+            summary.renamed,
+            [(PathBuf::from("configs/app.conf"), renamed.clone())]
+        );
+        assert_eq!(fs::read(&conf).unwrap(), b"local");
+        assert_eq!(fs::read(dest.join(renamed)).unwrap(), b"hello");
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_unsafe_paths() {
+        let dest = temp_dest("unsafe-paths");
+        let paths = ["../escaped", "configs/../../escaped", "/tmp/escaped"];
+
+        for path in paths.iter() {
+            match *unpack_error(&[(path, EntryType::Regular, None)], &dest).kind() {
+                errors::ErrorKind::UnsafeEntryPath(_) => (),
+                ref kind => panic!("unexpected error for {}: {:?}", path, kind),
+            }
+        }
+
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_link_escapes() {
+        let dest = temp_dest("link-escapes");
+        let links = [
+            ("up", EntryType::Symlink, Some("../../etc")),
+            ("configs/up", EntryType::Symlink, Some("../..")),
+            ("passwd", EntryType::Symlink, Some("/etc/passwd")),
+            ("passwd", EntryType::Link, Some("../passwd")),
+        ];
+
+        for link in links.iter() {
+            match *unpack_error(&[*link], &dest).kind() {
+                errors::ErrorKind::LinkEscape(..) => (),
+                ref kind => panic!("unexpected error for {:?}: {:?}", link, kind),
+            }
+        }
+
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_links_through_links() {
+        let dest = temp_dest("links-through-links");
+        let outside = temp_dest("links-through-links-target");
+        fs::write(outside.join("passwd"), b"").unwrap();
+        symlink(&outside, dest.join("out")).unwrap();
+
+        let links: &[&[(&str, EntryType, Option<&str>)]] = &[
+            &[("up", EntryType::Symlink, Some("out/.."))],
+            &[("passwd", EntryType::Symlink, Some("out/passwd"))],
+            &[("passwd", EntryType::Link, Some("out/passwd"))],
+            &[
+                ("a", EntryType::Symlink, Some(".")),
+                ("a/e", EntryType::Symlink, Some("..")),
+            ],
+            // `later` could still become a symlink
+            &[("up", EntryType::Symlink, Some("later/.."))],
+            &[
+                ("l1", EntryType::Symlink, Some("l2")),
+                ("l2", EntryType::Symlink, Some("l1")),
+                ("loop", EntryType::Symlink, Some("l1/x")),
+            ],
+        ];
+
+        for entries in links.iter() {
+            match *unpack_error(entries, &dest).kind() {
+                errors::ErrorKind::LinkEscape(..) => (),
+                ref kind => panic!("unexpected error for {:?}: {:?}", entries, kind),
+            }
+            for name in ["a", "l1", "l2"].iter() {
+                let _ = fs::remove_file(dest.join(name));
+            }
+        }
+
+        // Links may lead through links that stay inside
+        let mut archive = tar::Builder::new(Vec::new());
+        append_raw(&mut archive, "lib", EntryType::Symlink, Some("libfoo.so.1"));
+        append_raw(&mut archive, "current", EntryType::Symlink, Some("lib"));
+        append_raw(&mut archive, "a", EntryType::Symlink, Some("."));
+        append_raw(&mut archive, "a/b", EntryType::Symlink, Some("a/lib"));
+        let buf = archive.into_inner().unwrap();
+        unpack_archive(buf.as_slice(), &dest, &UnpackOptions::default()).unwrap();
+        assert_eq!(fs::read_link(dest.join("b")).unwrap(), PathBuf::from("a/lib"));
+
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 1);
+        fs::remove_dir_all(&dest).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_through_existing_symlink() {
+        let dest = temp_dest("existing-symlink");
+        let outside = temp_dest("existing-symlink-target");
+        symlink(&outside, dest.join("out")).unwrap();
+
+        match *unpack_error(&[("out/file", EntryType::Regular, None)], &dest).kind() {
+            errors::ErrorKind::UnsafeEntryPath(_) => (),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        fs::remove_dir_all(&dest).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_devices() {
+        let dest = temp_dest("devices");
+        match *unpack_error(&[("null", EntryType::Char, None)], &dest).kind() {
+            errors::ErrorKind::DeviceEntry(_) => (),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+
+        let mut archive = tar::Builder::new(Vec::new());
+        append_raw(&mut archive, "sda", EntryType::Block, None);
+        let buf = archive.into_inner().unwrap();
+
+        let opts = UnpackOptions {
+            skip_devices: true,
+            ..Default::default()
+        };
+        unpack_archive(buf.as_slice(), &dest, &opts).unwrap();
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_entry_names() {
+        let srcs = [
+            PathBuf::from("/etc/nginx"),
+            PathBuf::from("/etc/ssl/cert.pem"),
+        ];
+        let names = entry_names(&srcs).unwrap();
+        assert_eq!(names, [PathBuf::from("nginx"), PathBuf::from("cert.pem")]);
+
+        let srcs = [PathBuf::from("/srv/a/README"), PathBuf::from("/srv/b/README")];
+        match entry_names(&srcs) {
+            Err(errors::Error(errors::ErrorKind::DuplicateEntryName(ref name), _)) => {
+                assert_eq!(name, "README")
+            }
+            res => panic!("expected duplicate entry name, got {:?}", res),
+        }
+    }
+
+    fn packed_paths(srcs: &[PathBuf], opts: &PackOptions) -> Vec<String> {
+        let buf = pack_archive(srcs, Vec::new(), opts).unwrap();
+        list_archive(buf.as_slice())
+            .unwrap()
+            .iter()
+            .map(|e| {
+                let path = match display(&relative_path(&e.path).unwrap()) {
+                    ref path if path.is_empty() => ".".to_string(),
+                    path => path,
+                };
+                match e.entry_type {
+                    EntryType::Directory => format!("{}/", path),
+                    _ => path,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pack_filters() {
+        let src = temp_dest("pack-filters");
+        let project = src.join("project");
+        for dir in ["src/bin", "docs"].iter() {
+            fs::create_dir_all(project.join(dir)).unwrap();
+        }
+        for file in ["src/lib.rs", "src/bin/main.rs", "docs/guide.md"].iter() {
+            fs::write(project.join(file), b"").unwrap();
+        }
+        fs::write(src.join("notes.rs"), b"").unwrap();
+
+        let opts = |excludes: &[&str], includes: &[&str]| {
+            let strings = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            PackOptions {
+                filter: PathFilter::new(&strings(excludes), &strings(includes)).unwrap(),
+                ..Default::default()
+            }
+        };
+
+        // Only the directories leading to included files are kept
+        assert_eq!(
+            packed_paths(&[project.clone()], &opts(&["/src/lib.rs"], &["*.rs"])),
+            ["./", "src/", "src/bin/", "src/bin/main.rs"]
+        );
+        assert_eq!(
+            packed_paths(&[project.clone()], &opts(&["/bin", "docs"], &[])),
+            ["./", "src/", "src/bin/", "src/bin/main.rs", "src/lib.rs"]
+        );
+
+        // With several sources, patterns start at the archive root, above
+        // the directory names
+        assert_eq!(
+            packed_paths(
+                &[project.clone(), src.join("notes.rs")],
+                &opts(&["/project/src/bin"], &["/project/**/*.rs"])
+            ),
+            ["project/", "project/src/", "project/src/lib.rs", "notes.rs"]
+        );
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn test_pack_reader() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+
+        // Held in memory, and spooled to disk
+        for threshold in [SPOOL_THRESHOLD, 1000].iter() {
+            let buf = pack_spooled("dump.sql", data.as_slice(), Vec::new(), *threshold).unwrap();
+
+            let entries = list_archive(buf.as_slice()).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].path, PathBuf::from("dump.sql"));
+            assert_eq!(entries[0].size, data.len() as u64);
+            assert_eq!(entries[0].permissions(), "-rw-------");
+
+            assert_eq!(data, unpack_single_file(buf.as_slice(), Vec::new()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_unpack_single_file() {
+        let mut archive = tar::Builder::new(Vec::new());
+        append_raw(&mut archive, "configs", EntryType::Directory, None);
+        append_conf(&mut archive);
+        let buf = archive.into_inner().unwrap();
+        assert_eq!(b"hello".to_vec(), unpack_single_file(buf.as_slice(), Vec::new()).unwrap());
+
+        let unpack_error = |entries: &[(&str, EntryType, Option<&str>)]| {
+            let mut archive = tar::Builder::new(Vec::new());
+            append_conf(&mut archive);
+            for (path, entry_type, link_name) in entries.iter() {
+                append_raw(&mut archive, path, *entry_type, *link_name);
+            }
+            let buf = archive.into_inner().unwrap();
+
+            unpack_single_file(buf.as_slice(), Vec::new()).unwrap_err()
+        };
+
+        match *unpack_error(&[("other.conf", EntryType::Regular, None)]).kind() {
+            errors::ErrorKind::NotSingleFile => (),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+
+        let links = [
+            ("app.conf", EntryType::Symlink, Some("configs/app.conf")),
+            ("copy.conf", EntryType::Link, Some("configs/app.conf")),
+        ];
+        for link in links.iter() {
+            match *unpack_error(&[*link]).kind() {
+                errors::ErrorKind::MemberNotFile(ref path) => assert_eq!(link.0, path),
+                ref kind => panic!("unexpected error for {:?}: {:?}", link, kind),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cat_member() {
+        let mut archive = tar::Builder::new(Vec::new());
+        append_raw(&mut archive, "configs", EntryType::Directory, None);
+        append_conf(&mut archive);
+        append_raw(&mut archive, "copy.conf", EntryType::Link, Some("./configs/app.conf"));
+        append_raw(&mut archive, "copy2.conf", EntryType::Link, Some("copy.conf"));
+        append_raw(&mut archive, "dangling.conf", EntryType::Link, Some("gone.conf"));
+
+        // A later entry of the same name, which hard links made before it
+        // do not see
+        let mut header = tar::Header::new_gnu();
+        header.set_path("configs/app.conf").unwrap();
+        header.set_size(7);
+        header.set_mode(0o640);
+        header.set_entry_type(EntryType::Regular);
+        // Old archives mark files with `NUL` rather than `0`
+        header.as_old_mut().linkflag = [0];
+        header.set_cksum();
+        archive.append(&header, "goodbye".as_bytes()).unwrap();
+        let buf = archive.into_inner().unwrap();
+
+        let cat = |member: &str| cat_member(|| Ok(buf.as_slice()), Path::new(member), Vec::new());
+
+        assert_eq!(b"goodbye".to_vec(), cat("./configs/app.conf").unwrap());
+        assert_eq!(b"hello".to_vec(), cat("copy.conf").unwrap());
+        assert_eq!(b"hello".to_vec(), cat("copy2.conf").unwrap());
+
+        match *cat("configs").unwrap_err().kind() {
+            errors::ErrorKind::MemberNotFile(ref path) => assert_eq!("configs", path),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+
+        for (member, missing) in [("app.conf", "app.conf"), ("dangling.conf", "gone.conf")].iter() {
+            match *cat(member).unwrap_err().kind() {
+                errors::ErrorKind::MemberNotFound(ref path) => assert_eq!(missing, path),
+                ref kind => panic!("unexpected error: {:?}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_archive() {
+        let buf = make_archive();
+        let stats = verify_archive(buf.as_slice()).unwrap();
+        assert_eq!(stats, ArchiveStats { entries: 1, bytes: 5 });
+
+        // A header with a bad checksum is rejected.
+        let mut corrupt = buf.clone();
+        corrupt[0] ^= 0xff;
+        assert!(verify_archive(corrupt.as_slice()).is_err());
+    }
+}
@@ -1,18 +1,243 @@
 use rand::{prng, seq, thread_rng, SeedableRng};
+use sodiumoxide::crypto::generichash;
+
+use errors;
 
 const PASSWORD_WORD_COUNT: usize = 6;
 
-// generate a reasonable password
+/// Largest edit distance at which a dictionary word is suggested for a
+/// mistyped one.
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+// generate a reasonable password, with a checksum word at the end so that
+// typos are caught before a key is derived from it
 pub fn generate_password() -> String {
     let mut rng = prng::chacha::ChaChaRng::from_rng(thread_rng()).unwrap();
-    let sample = seq::sample_iter(&mut rng, WORDS.into_iter(), PASSWORD_WORD_COUNT).unwrap();
+    let mut sample = seq::sample_iter(&mut rng, 0..WORDS.len(), PASSWORD_WORD_COUNT).unwrap();
+    let checksum = checksum_word(&sample);
+    sample.push(checksum);
     sample
         .into_iter()
-        .map(|x| String::from(*x))
+        .map(|i| String::from(WORDS[i]))
         .collect::<Vec<String>>()
         .join("-")
 }
 
+/// Picks the checksum word for the words at `indices` from the BLAKE2b hash
+/// of their indices.
+fn checksum_word(indices: &[usize]) -> usize {
+    let mut input = Vec::with_capacity(indices.len() * 2);
+    for index in indices {
+        input.extend_from_slice(&(*index as u16).to_be_bytes());
+    }
+
+    let digest = generichash::hash(&input, None, None).unwrap();
+    let digest = digest.as_ref();
+    u16::from_be_bytes([digest[0], digest[1]]) as usize % WORDS.len()
+}
+
+/// Whether the last of `indices` is the checksum word of the others.
+fn checksum_matches(indices: &[usize]) -> bool {
+    checksum_word(&indices[..PASSWORD_WORD_COUNT]) == indices[PASSWORD_WORD_COUNT]
+}
+
+/// Checks a secret made by `generate_password` for mistyped words, so that
+/// a typo is reported before any key is derived from it. Secrets made
+/// before the checksum word was added only have their words looked up, and
+/// anything not shaped like a generated secret is left alone.
+pub fn check_password(password: &str) -> errors::Result<()> {
+    let words: Vec<&str> = password.split('-').collect();
+    let shaped = words
+        .iter()
+        .all(|w| !w.is_empty() && w.chars().all(|c| c.is_ascii_alphabetic()));
+    let has_checksum = words.len() == PASSWORD_WORD_COUNT + 1;
+    if !shaped || (words.len() != PASSWORD_WORD_COUNT && !has_checksum) {
+        return Ok(());
+    }
+
+    let indices: Vec<Option<usize>> = words
+        .iter()
+        .map(|word| WORDS.iter().position(|w| w == word))
+        .collect();
+    let unknown: Vec<usize> = (0..words.len()).filter(|&i| indices[i].is_none()).collect();
+
+    // A word that is not in the dictionary is mistyped for sure; the
+    // checksum narrows down what it should have been when it is the only one
+    if let Some(&position) = unknown.first() {
+        let mut nearest = Vec::new();
+        if has_checksum && unknown.len() == 1 {
+            nearest = checksum_fixes(&words, &indices, position).1;
+        }
+        if nearest.is_empty() {
+            nearest = nearest_words(words[position]);
+        }
+
+        bail!(errors::ErrorKind::MistypedWord(
+            position + 1,
+            nearest.join(", ")
+        ));
+    }
+
+    let known: Vec<usize> = indices.iter().map(|i| i.unwrap()).collect();
+    if !has_checksum || checksum_matches(&known) {
+        return Ok(());
+    }
+
+    // Every word is in the dictionary, so the mistyped one is likely the
+    // one closest to a word that makes the checksum match
+    let mut fixes = Vec::new();
+    for position in 0..words.len() {
+        let (distance, nearest) = checksum_fixes(&words, &indices, position);
+        if !nearest.is_empty() {
+            fixes.push((distance, position, nearest));
+        }
+    }
+
+    fixes.sort_by_key(|&(distance, _, _)| distance);
+    let unambiguous = match fixes.get(1) {
+        Some(&(distance, _, _)) => distance > fixes[0].0,
+        None => true,
+    };
+    if let (Some(&(_, position, ref nearest)), true) = (fixes.first(), unambiguous) {
+        bail!(errors::ErrorKind::MistypedWord(
+            position + 1,
+            nearest.join(", ")
+        ));
+    }
+
+    bail!(errors::ErrorKind::MistypedSecret)
+}
+
+/// The dictionary words that are closest to the word at `position` and make
+/// the checksum match in its place, along with their edit distance.
+fn checksum_fixes(
+    words: &[&str],
+    indices: &[Option<usize>],
+    position: usize,
+) -> (usize, Vec<&'static str>) {
+    let word = words[position].to_uppercase();
+    for distance in 1..=MAX_SUGGESTION_DISTANCE {
+        let mut fixes = Vec::new();
+        for (index, candidate) in WORDS.iter().enumerate() {
+            if edit_distance(&word, candidate) != distance {
+                continue;
+            }
+
+            let fixed: Vec<usize> = indices
+                .iter()
+                .enumerate()
+                .map(|(i, known)| if i == position { index } else { known.unwrap() })
+                .collect();
+            if checksum_matches(&fixed) {
+                fixes.push(*candidate);
+            }
+        }
+
+        if !fixes.is_empty() {
+            return (distance, fixes);
+        }
+    }
+
+    (0, Vec::new())
+}
+
+/// The dictionary words closest to `word`.
+fn nearest_words(word: &str) -> Vec<&'static str> {
+    let word = word.to_uppercase();
+    let distances: Vec<usize> = WORDS.iter().map(|w| edit_distance(&word, w)).collect();
+    let closest = *distances.iter().min().unwrap();
+
+    WORDS
+        .iter()
+        .zip(distances)
+        .filter(|&(_, d)| d == closest)
+        .map(|(w, _)| *w)
+        .take(5)
+        .collect()
+}
+
+/// Edit distance between two words, counting a swap of adjacent letters as
+/// a single edit, since that is a common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // d[i][j] is the distance between the first i letters of `a` and the
+    // first j letters of `b`
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+/// Bits encoded by one word of the dictionary.
+const BITS_PER_WORD: usize = 11;
+
+/// Looks up `word` in the dictionary, ignoring case.
+pub fn word_index(word: &str) -> Option<usize> {
+    let word = word.to_uppercase();
+    WORDS.iter().position(|w| *w == word)
+}
+
+/// Renders `bytes` as dictionary words, 11 bits to a word. The last word is
+/// padded with zero bits.
+pub fn encode_words(bytes: &[u8]) -> Vec<&'static str> {
+    let mut words = Vec::with_capacity((bytes.len() * 8 + BITS_PER_WORD - 1) / BITS_PER_WORD);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        acc = (acc << 8) | u32::from(*byte);
+        bits += 8;
+        if bits >= BITS_PER_WORD {
+            bits -= BITS_PER_WORD;
+            words.push(WORDS[(acc >> bits) as usize & 0x7ff]);
+        }
+    }
+    if bits > 0 {
+        words.push(WORDS[(acc << (BITS_PER_WORD - bits)) as usize & 0x7ff]);
+    }
+
+    words
+}
+
+/// Reverses `encode_words`. Returns `None` if a word is not in the
+/// dictionary or the padding is not zero.
+pub fn decode_words(words: &[&str]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(words.len() * BITS_PER_WORD / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for word in words {
+        acc = (acc << BITS_PER_WORD) | word_index(word)? as u32;
+        bits += BITS_PER_WORD;
+        while bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    if acc & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+
+    Some(bytes)
+}
+
 static WORDS: [&str; 2048] = [
     "A", "ABE", "ACE", "ACT", "AD", "ADA", "ADD", "AGO", "AID", "AIM", "AIR", "ALL", "ALP", "AM",
     "AMY", "AN", "ANA", "AND", "ANN", "ANT", "ANY", "APE", "APS", "APT", "ARC", "ARE", "ARK",
@@ -181,3 +406,63 @@ static WORDS: [&str; 2048] = [
     "WONT", "WOOD", "WOOL", "WORD", "WORE", "WORK", "WORM", "WORN", "WOVE", "WRIT", "WYNN", "YALE",
     "YANG", "YANK", "YARD", "YARN", "YAWL", "YAWN", "YEAH", "YEAR", "YELL", "YOGA", "YOKE",
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mistyped(password: &str) -> (usize, String) {
+        match check_password(password) {
+            Err(errors::Error(errors::ErrorKind::MistypedWord(position, nearest), _)) => {
+                (position, nearest)
+            }
+            res => panic!(format!("expected `MistypedWord`, got: {:?}", res)),
+        }
+    }
+
+    #[test]
+    fn test_checksum_word() {
+        let password = generate_password();
+        let words: Vec<&str> = password.split('-').collect();
+        assert_eq!(PASSWORD_WORD_COUNT + 1, words.len());
+        check_password(&password).unwrap();
+
+        // Secrets without a checksum word, and other secrets, still pass
+        check_password(&words[..PASSWORD_WORD_COUNT].join("-")).unwrap();
+        check_password("A-B-C").unwrap();
+        check_password("correct horse battery staple").unwrap();
+    }
+
+    #[test]
+    fn test_mistyped_words() {
+        let words = ["BARK", "COOL", "DEAF", "GASH", "LIMB", "SOUR"];
+        let indices: Vec<usize> = words.iter().map(|w| word_index(w).unwrap()).collect();
+        let password = format!("{}-{}", words.join("-"), WORDS[checksum_word(&indices)]);
+        check_password(&password).unwrap();
+
+        // A word that is not in the dictionary is pointed out right away
+        let (position, nearest) = mistyped(&password.replace("DEAF", "DEFA"));
+        assert_eq!(3, position);
+        assert!(nearest.contains("DEAF"));
+        assert_eq!(
+            (4, "GASH".to_string()),
+            mistyped(&password.replace("GASH", "gash"))
+        );
+
+        // A dictionary word that breaks the checksum is found through it;
+        // no word nearer to LIME than LIMB fixes the checksum there
+        assert_eq!(
+            (5, "LIMB".to_string()),
+            mistyped(&password.replace("LIMB", "LIME"))
+        );
+    }
+
+    #[test]
+    fn test_encode_words() {
+        let bytes = [0xde, 0xad, 0xbe, 0xef, 0x01];
+        let words = encode_words(&bytes);
+        assert_eq!(4, words.len());
+        assert_eq!(Some(bytes.to_vec()), decode_words(&words));
+        assert_eq!(None, decode_words(&["NOTAWORD"]));
+    }
+}
@@ -0,0 +1,360 @@
+//! Shamir secret sharing over GF(256).
+//!
+//! A tarbox sealed with shares has an ordinary secret key slot, but its
+//! secret is never shown. Instead it is split into `n` shares, any
+//! `threshold` of which recover it. Each byte of the secret is the constant
+//! term of its own random polynomial of degree `threshold - 1`, and share
+//! `x` holds the value of every polynomial at `x`.
+//!
+//! A share is written as 16 words of the password dictionary, encoding:
+//!
+//! ```text
+//! +--------+-----------+-------+----------+----------+
+//! | SET ID | THRESHOLD | INDEX |  VALUES  | CHECKSUM |
+//! +--------+-----------+-------+----------+----------+
+//! | [u8;2] |    u8     |  u8   | [u8; 16] | [u8; 2]  |
+//! +--------+-----------+-------+----------+----------+
+//! ```
+//!
+//! The set ID is shared by all shares of one secret, so that shares of
+//! different secrets are not mixed, and the checksum catches mistyped words.
+
+use sodiumoxide::crypto::generichash;
+use sodiumoxide::randombytes::randombytes;
+use std::fmt;
+
+use errors;
+use password;
+
+/// Size of the secret that is split into shares.
+pub const SECRET_BYTES: usize = 16;
+
+const SET_ID_BYTES: usize = 2;
+const CHECKSUM_BYTES: usize = 2;
+const SHARE_BYTES: usize = SET_ID_BYTES + 2 + SECRET_BYTES + CHECKSUM_BYTES;
+
+/// One share of a secret.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Share {
+    set_id: [u8; SET_ID_BYTES],
+    threshold: u8,
+    index: u8,
+    values: [u8; SECRET_BYTES],
+}
+
+impl Share {
+    /// Number of shares needed to recover the secret.
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Position of the share, from 1.
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(SHARE_BYTES);
+        body.extend_from_slice(&self.set_id);
+        body.push(self.threshold);
+        body.push(self.index);
+        body.extend_from_slice(&self.values);
+        body
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.body();
+        let checksum = checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    /// Parses a share written as words, or returns `None` if a word is
+    /// unknown or the checksum does not match.
+    fn from_words(words: &str) -> Option<Share> {
+        let words: Vec<&str> = words
+            .split(|c: char| c == '-' || c.is_whitespace())
+            .filter(|w| !w.is_empty())
+            .collect();
+        let bytes = password::decode_words(&words)?;
+        if bytes.len() != SHARE_BYTES {
+            return None;
+        }
+
+        let (body, sum) = bytes.split_at(SHARE_BYTES - CHECKSUM_BYTES);
+        if checksum(body) != sum {
+            return None;
+        }
+
+        let mut set_id = [0; SET_ID_BYTES];
+        let mut values = [0; SECRET_BYTES];
+        set_id.copy_from_slice(&body[..SET_ID_BYTES]);
+        values.copy_from_slice(&body[SET_ID_BYTES + 2..]);
+        let (threshold, index) = (body[SET_ID_BYTES], body[SET_ID_BYTES + 1]);
+        if threshold < 2 || index == 0 {
+            return None;
+        }
+
+        Some(Share {
+            set_id: set_id,
+            threshold: threshold,
+            index: index,
+            values: values,
+        })
+    }
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", password::encode_words(&self.to_bytes()).join("-"))
+    }
+}
+
+fn checksum(body: &[u8]) -> [u8; CHECKSUM_BYTES] {
+    let digest = generichash::hash(body, None, None).unwrap();
+    let mut sum = [0; CHECKSUM_BYTES];
+    sum.copy_from_slice(&digest.as_ref()[..CHECKSUM_BYTES]);
+    sum
+}
+
+/// Multiplies in GF(256) with the AES polynomial.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+
+    product
+}
+
+/// Inverts a non-zero element of GF(256), as `a^254`.
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    for _ in 0..254 {
+        result = gf_mul(result, a);
+    }
+
+    result
+}
+
+/// Evaluates at `x` the polynomial through `points`, whose x coordinates
+/// must be distinct.
+fn interpolate(points: &[(u8, u8)], x: u8) -> u8 {
+    let mut y = 0;
+    for (j, &(xj, yj)) in points.iter().enumerate() {
+        let mut basis = 1;
+        for (m, &(xm, _)) in points.iter().enumerate() {
+            if m != j {
+                // Subtraction is XOR in GF(2^8)
+                basis = gf_mul(basis, gf_mul(x ^ xm, gf_inv(xj ^ xm)));
+            }
+        }
+        y ^= gf_mul(yj, basis);
+    }
+
+    y
+}
+
+/// Checks that a secret can be split into `shares` shares, any `threshold`
+/// of which recover it.
+pub fn check_threshold(threshold: usize, shares: usize) -> errors::Result<()> {
+    if threshold < 2 || threshold > shares || shares > 255 {
+        bail!(errors::ErrorKind::InvalidShareThreshold(threshold, shares));
+    }
+
+    Ok(())
+}
+
+/// Splits `secret` into `shares` shares, any `threshold` of which recover
+/// it.
+pub fn split(
+    secret: &[u8; SECRET_BYTES],
+    threshold: usize,
+    shares: usize,
+) -> errors::Result<Vec<Share>> {
+    check_threshold(threshold, shares)?;
+
+    let mut set_id = [0; SET_ID_BYTES];
+    set_id.copy_from_slice(&randombytes(SET_ID_BYTES));
+
+    // One polynomial per byte of the secret, with the byte as constant term
+    let coefficients: Vec<Vec<u8>> = secret
+        .iter()
+        .map(|byte| {
+            let mut poly = vec![*byte];
+            poly.extend(randombytes(threshold - 1));
+            poly
+        })
+        .collect();
+
+    Ok((1..=shares as u8)
+        .map(|x| {
+            let mut values = [0; SECRET_BYTES];
+            for (value, poly) in values.iter_mut().zip(&coefficients) {
+                // Horner's rule
+                *value = poly.iter().rev().fold(0, |acc, c| gf_mul(acc, x) ^ c);
+            }
+
+            Share {
+                set_id: set_id,
+                threshold: threshold as u8,
+                index: x,
+                values: values,
+            }
+        })
+        .collect())
+}
+
+/// Recovers the secret from `shares`. Shares beyond the threshold are
+/// checked against the recovered secret, so a wrong one is caught rather
+/// than silently producing a different secret.
+pub fn combine(shares: &[Share]) -> errors::Result<[u8; SECRET_BYTES]> {
+    let first = match shares.first() {
+        Some(first) => first,
+        None => bail!(errors::ErrorKind::NotEnoughShares(2, 0)),
+    };
+
+    for (i, share) in shares.iter().enumerate() {
+        if share.set_id != first.set_id || share.threshold != first.threshold {
+            bail!(errors::ErrorKind::MismatchedShares(i + 1));
+        }
+        if shares[..i].iter().any(|s| s.index == share.index) {
+            bail!(errors::ErrorKind::DuplicateShare(i + 1));
+        }
+    }
+
+    let threshold = first.threshold as usize;
+    if shares.len() < threshold {
+        bail!(errors::ErrorKind::NotEnoughShares(threshold, shares.len()));
+    }
+
+    let (used, extra) = shares.split_at(threshold);
+    let mut secret = [0; SECRET_BYTES];
+    for (i, byte) in secret.iter_mut().enumerate() {
+        let points: Vec<(u8, u8)> = used.iter().map(|s| (s.index, s.values[i])).collect();
+        *byte = interpolate(&points, 0);
+
+        for share in extra {
+            if interpolate(&points, share.index) != share.values[i] {
+                bail!(errors::ErrorKind::InconsistentShares);
+            }
+        }
+    }
+
+    Ok(secret)
+}
+
+/// Parses shares written as words. Positions in errors count from 1, in the
+/// order the shares were given.
+pub fn parse_shares(shares: &[String]) -> errors::Result<Vec<Share>> {
+    shares
+        .iter()
+        .enumerate()
+        .map(|(i, words)| {
+            Share::from_words(words).ok_or_else(|| errors::ErrorKind::InvalidShare(i + 1).into())
+        })
+        .collect()
+}
+
+/// Generates a secret and splits it into shares, returning the secret as
+/// the password of its key slot along with the shares.
+pub fn generate(threshold: usize, shares: usize) -> errors::Result<(String, Vec<Share>)> {
+    let mut secret = [0; SECRET_BYTES];
+    secret.copy_from_slice(&randombytes(SECRET_BYTES));
+    let shares = split(&secret, threshold, shares)?;
+
+    Ok((secret_password(&secret), shares))
+}
+
+/// Recovers the password of the key slot from shares written as words.
+pub fn recover(shares: &[String]) -> errors::Result<String> {
+    let secret = combine(&parse_shares(shares)?)?;
+    Ok(secret_password(&secret))
+}
+
+fn secret_password(secret: &[u8; SECRET_BYTES]) -> String {
+    password::encode_words(secret).join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(shares: &[Share]) -> Vec<String> {
+        shares.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_gf() {
+        assert_eq!(0xc1, gf_mul(0x57, 0x83));
+        for a in 1..=255u8 {
+            assert_eq!(1, gf_mul(a, gf_inv(a)));
+        }
+    }
+
+    #[test]
+    fn test_split_combine() {
+        let secret = [0x5a; SECRET_BYTES];
+        let shares = split(&secret, 3, 5).unwrap();
+        assert_eq!(5, shares.len());
+
+        // Any three shares, in any order, recover the secret
+        for picked in &[[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let picked: Vec<Share> = picked.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(secret, combine(&picked).unwrap());
+        }
+        assert_eq!(secret, combine(&shares).unwrap());
+
+        let written = words(&shares);
+        assert_eq!(16, written[0].split('-').count());
+        assert_eq!(shares, parse_shares(&written).unwrap());
+        assert_eq!(
+            shares[..1].to_vec(),
+            parse_shares(&[written[0].to_lowercase().replace('-', " ")]).unwrap()
+        );
+
+        assert!(split(&secret, 1, 5).is_err());
+        assert!(split(&secret, 4, 3).is_err());
+    }
+
+    #[test]
+    fn test_bad_shares() {
+        let shares = split(&[0x1; SECRET_BYTES], 2, 3).unwrap();
+        let other = split(&[0x2; SECRET_BYTES], 2, 3).unwrap();
+
+        let check = |shares: &[Share], expected: errors::ErrorKind| match combine(shares) {
+            Err(errors::Error(ref kind, _)) if kind.to_string() == expected.to_string() => (),
+            res => panic!(format!("expected `{}`, got: {:?}", expected, res)),
+        };
+
+        check(&shares[..1], errors::ErrorKind::NotEnoughShares(2, 1));
+        check(
+            &[shares[0].clone(), shares[0].clone()],
+            errors::ErrorKind::DuplicateShare(2),
+        );
+        check(
+            &[shares[0].clone(), other[1].clone()],
+            errors::ErrorKind::MismatchedShares(2),
+        );
+
+        let mut wrong = shares[2].clone();
+        wrong.values[3] ^= 0x1;
+        check(
+            &[shares[0].clone(), shares[1].clone(), wrong],
+            errors::ErrorKind::InconsistentShares,
+        );
+
+        // A mistyped word fails the checksum
+        let mut written: Vec<String> = shares[0].to_string().split('-').map(String::from).collect();
+        written[4] = if written[4] == "A" { "I" } else { "A" }.to_string();
+        assert!(parse_shares(&[written.join("-")]).is_err());
+    }
+}
@@ -1,95 +1,735 @@
+//! Tarbox header attributes.
+//!
+//! Version 1 and 2 headers carry a fixed-size attribute block. From version 3
+//! on, the attribute block is self-describing: a block version byte followed
+//! by typed, length-prefixed entries, terminated by the header's `NUL` byte.
+//!
+//! ```text
+//! +---------+-------+---------+---------+-----+---------+-----+
+//! | ATTRVER |  TAG  |   LEN   |  VALUE  | ... |  TAG N  | NUL |
+//! +---------+-------+---------+---------+-----+---------+-----+
+//! |   u8    |  u8   | u16 BE  | [u8]    |     |   ...   | u8  |
+//! +---------+-------+---------+---------+-----+---------+-----+
+//! ```
+//!
+//! Tags with the `TAG_CRITICAL` bit set must be understood by the reader;
+//! unknown critical tags are an error, while unknown optional tags are
+//! skipped. This lets new metadata be added without breaking old readers.
+//!
+//! Version 5 headers hold key slots instead of a salt and KDF. The key slots
+//! entry is left out of the header authentication, so slots can be replaced
+//! without re-encrypting the payload; each slot authenticates itself.
+
+use base64;
+use std::cmp;
+use std::io::Read;
+use std::str::FromStr;
+
 use super::{
     errors,
-    secret::{TarboxSecret, NONCEBYTES, SALTBYTES},
+    keyslot::{self, KeySlot},
+    recipient::WRAPPED_KEY_BYTES,
+    secret::{Kdf, KdfAlgorithm, TarboxSecret, NONCEBYTES, SALTBYTES},
 };
 
 pub type NonceBytes = [u8; NONCEBYTES];
 pub type SaltBytes = [u8; SALTBYTES];
 
-pub const VERSION: u8 = 0x1;
+/// Header version of tarboxes sealed as a single `secretbox`.
+pub const VERSION_SECRETBOX: u8 = 0x1;
+
+/// Header version of tarboxes sealed as a chunked `secretstream`.
+pub const VERSION_SECRETSTREAM: u8 = 0x2;
+
+/// Header version with a self-describing attribute block.
+pub const VERSION_TLV: u8 = 0x3;
+
+/// Header version with a self-describing attribute block that is also
+/// authenticated as associated data of the encrypted stream.
+pub const VERSION_AUTHENTICATED: u8 = 0x4;
+
+/// Header version whose payload is encrypted with a random data key held
+/// in key slots.
+pub const VERSION_KEY_SLOTS: u8 = 0x5;
+
+/// Header version written by new tarboxes.
+pub const VERSION: u8 = VERSION_KEY_SLOTS;
+
+/// Version of the self-describing attribute block layout.
+pub const ATTRIBUTES_VERSION: u8 = 0x1;
+
+/// Marks the end of the attribute block; this is the header's `NUL`.
+pub const TAG_END: u8 = 0x00;
+
+/// Set on tags that a reader must understand to open the tarbox.
+pub const TAG_CRITICAL: u8 = 0x80;
+
+pub const TAG_CREATED: u8 = 0x01;
+pub const TAG_ORIGINAL_NAME: u8 = 0x02;
+pub const TAG_SALT: u8 = TAG_CRITICAL | 0x01;
+pub const TAG_CIPHER: u8 = TAG_CRITICAL | 0x02;
+pub const TAG_COMPRESSION: u8 = TAG_CRITICAL | 0x03;
+pub const TAG_KDF: u8 = TAG_CRITICAL | 0x04;
+pub const TAG_KDF_PARAMS: u8 = TAG_CRITICAL | 0x05;
+pub const TAG_COMPRESSION_BLOCKS: u8 = TAG_CRITICAL | 0x06;
+pub const TAG_RECIPIENTS: u8 = TAG_CRITICAL | 0x07;
+pub const TAG_KEY_SLOTS: u8 = TAG_CRITICAL | 0x08;
+
+/// Encryption construction used for the tarbox payload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+    /// A single `crypto_secretbox_xsalsa20poly1305` box.
+    XSalsa20Poly1305,
+    /// A chunked `crypto_secretstream_xchacha20poly1305` stream.
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn id(&self) -> u8 {
+        match *self {
+            Cipher::XSalsa20Poly1305 => 0x1,
+            Cipher::XChaCha20Poly1305 => 0x2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> errors::Result<Cipher> {
+        match id {
+            0x1 => Ok(Cipher::XSalsa20Poly1305),
+            0x2 => Ok(Cipher::XChaCha20Poly1305),
+            _ => bail!(errors::ErrorKind::InvalidAttribute(TAG_CIPHER)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Cipher::XSalsa20Poly1305 => "xsalsa20poly1305",
+            Cipher::XChaCha20Poly1305 => "xchacha20poly1305",
+        }
+    }
+}
+
+/// Compression applied to the archive before encryption. Headers older
+/// than version 3 do not record it; those tarboxes are always gzip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    None,
+}
+
+impl Compression {
+    pub fn id(&self) -> u8 {
+        match *self {
+            Compression::Gzip => 0x1,
+            Compression::Zstd => 0x2,
+            Compression::Xz => 0x3,
+            Compression::None => 0x4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> errors::Result<Compression> {
+        match id {
+            0x1 => Ok(Compression::Gzip),
+            0x2 => Ok(Compression::Zstd),
+            0x3 => Ok(Compression::Xz),
+            0x4 => Ok(Compression::None),
+            _ => bail!(errors::ErrorKind::InvalidAttribute(TAG_COMPRESSION)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::None => "none",
+        }
+    }
+
+    /// The lowest and highest compression level accepted, or `None` when
+    /// the level cannot be chosen.
+    pub fn levels(&self) -> Option<(u32, u32)> {
+        match *self {
+            Compression::Gzip => Some((0, 9)),
+            Compression::Zstd => Some((1, 22)),
+            Compression::Xz => Some((0, 9)),
+            Compression::None => None,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            "none" => Ok(Compression::None),
+            _ => Err(format!("unknown compression: {}", s)),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Attributes {
-    nonce: NonceBytes,
-    salt: SaltBytes,
+    version: u8,
+    nonce: Option<NonceBytes>,
+    salt: Option<SaltBytes>,
+    kdf: Kdf,
+    cipher: Cipher,
+    compression: Compression,
+    compression_blocks: Option<u32>,
+    key_slots: Vec<KeySlot>,
+    created: Option<u64>,
+    original_name: Option<String>,
 }
 
 impl Attributes {
-    pub fn new(crypto_nonce: NonceBytes, kdf_salt: SaltBytes) -> Attributes {
+    /// Returns attributes for a version 4 header, whose key is derived
+    /// from the secret and `kdf_salt`.
+    pub fn new(kdf_salt: SaltBytes) -> Attributes {
         Attributes {
-            nonce: crypto_nonce,
-            salt: kdf_salt,
+            version: VERSION_AUTHENTICATED,
+            nonce: None,
+            salt: Some(kdf_salt),
+            kdf: Kdf::legacy(),
+            cipher: Cipher::XChaCha20Poly1305,
+            compression: Compression::Gzip,
+            compression_blocks: None,
+            key_slots: Vec::new(),
+            created: None,
+            original_name: None,
         }
     }
 
-    pub fn empty() -> Self {
+    /// Returns attributes for the current header version, whose data key is
+    /// wrapped in `key_slots`.
+    pub fn with_key_slots(key_slots: Vec<KeySlot>) -> Attributes {
+        Attributes {
+            version: VERSION,
+            nonce: None,
+            salt: None,
+            kdf: Kdf::legacy(),
+            cipher: Cipher::XChaCha20Poly1305,
+            compression: Compression::Gzip,
+            compression_blocks: None,
+            key_slots: key_slots,
+            created: None,
+            original_name: None,
+        }
+    }
+
+    /// Returns attributes for a version 1 (`secretbox`) header, which
+    /// also carries the crypto nonce.
+    pub fn new_secretbox(crypto_nonce: NonceBytes, kdf_salt: SaltBytes) -> Attributes {
         Attributes {
-            nonce: [0; NONCEBYTES],
-            salt: [0; SALTBYTES],
+            version: VERSION_SECRETBOX,
+            nonce: Some(crypto_nonce),
+            salt: Some(kdf_salt),
+            kdf: Kdf::legacy(),
+            cipher: Cipher::XSalsa20Poly1305,
+            compression: Compression::Gzip,
+            compression_blocks: None,
+            key_slots: Vec::new(),
+            created: None,
+            original_name: None,
         }
     }
 
-    pub fn version() -> u8 {
-        VERSION
+    pub fn empty() -> Self {
+        Attributes::new([0; SALTBYTES])
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns the size of the attribute block for the fixed-size
+    /// header versions.
+    pub fn attr_block_size(version: u8) -> errors::Result<usize> {
+        match version {
+            VERSION_SECRETBOX => Ok((NONCEBYTES + SALTBYTES) as usize),
+            VERSION_SECRETSTREAM => Ok(SALTBYTES as usize),
+            _ => bail!(errors::ErrorKind::VersionMismatch(VERSION, version)),
+        }
+    }
+
+    /// Whether the raw header bytes are bound to the encrypted stream as
+    /// associated data.
+    pub fn authenticates_header(&self) -> bool {
+        self.version >= VERSION_AUTHENTICATED
+    }
+
+    /// The `secretbox` nonce. Only version 1 headers carry one; chunked
+    /// tarboxes keep their stream header at the front of the payload.
+    pub fn nonce(&self) -> Option<&NonceBytes> {
+        self.nonce.as_ref()
+    }
+
+    /// The salt the key is derived with. Headers with key slots keep a
+    /// salt in each secret slot instead.
+    pub fn salt(&self) -> Option<&SaltBytes> {
+        self.salt.as_ref()
+    }
+
+    pub fn encoded_nonce(&self) -> Option<String> {
+        self.nonce.map(|nonce| base64::encode(&nonce))
+    }
+
+    pub fn encoded_salt(&self) -> Option<String> {
+        self.salt.map(|salt| base64::encode(&salt))
+    }
+
+    /// The KDF used to derive the key from the secret and salt. Tarboxes
+    /// that do not record one use `Kdf::legacy()`.
+    pub fn kdf(&self) -> &Kdf {
+        &self.kdf
     }
 
-    pub fn attr_block_size() -> usize {
-        (NONCEBYTES + SALTBYTES) as usize
+    pub fn set_kdf(&mut self, kdf: Kdf) {
+        self.kdf = kdf;
     }
 
-    pub fn nonce(&self) -> &NonceBytes {
-        &self.nonce
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
     }
 
-    pub fn salt(&self) -> &SaltBytes {
-        &self.salt
+    pub fn compression(&self) -> Compression {
+        self.compression
     }
 
-    pub fn from_bytes(source: Vec<u8>) -> errors::Result<Attributes> {
-        let expected: usize = NONCEBYTES + SALTBYTES;
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Size of the blocks the archive was split into and compressed one by
+    /// one, or `None` when it was compressed as a single stream.
+    pub fn compression_blocks(&self) -> Option<u32> {
+        self.compression_blocks
+    }
+
+    pub fn set_compression_blocks(&mut self, block_size: u32) {
+        self.compression_blocks = Some(block_size);
+    }
+
+    /// The slots holding the data key. Empty when the key is derived from
+    /// the secret and salt.
+    pub fn key_slots(&self) -> &[KeySlot] {
+        &self.key_slots
+    }
+
+    pub fn set_key_slots(&mut self, key_slots: Vec<KeySlot>) {
+        self.key_slots = key_slots;
+    }
+
+    /// Seconds since the Unix epoch at which the tarbox was sealed.
+    pub fn created(&self) -> Option<u64> {
+        self.created
+    }
+
+    pub fn set_created(&mut self, created: u64) {
+        self.created = Some(created);
+    }
+
+    /// Name of the file or directory the tarbox was sealed from.
+    pub fn original_name(&self) -> Option<&String> {
+        self.original_name.as_ref()
+    }
+
+    pub fn set_original_name(&mut self, name: String) {
+        self.original_name = Some(name);
+    }
+
+    /// Reads the attribute block for `version` from `source`, up to and
+    /// including the `NUL` byte that ends the tarbox header.
+    pub fn read_from<R: Read>(version: u8, source: &mut R) -> errors::Result<Attributes> {
+        if version < VERSION_TLV {
+            let mut attrs_data = vec![0; Attributes::attr_block_size(version)?];
+            source.read_exact(attrs_data.as_mut_slice())?;
+
+            // The next byte we read should be a `NUL`.
+            let next = read_byte(source)?;
+            if next != 0x0 {
+                bail!(errors::ErrorKind::ExpectedNullByte(next));
+            }
+
+            return Attributes::from_bytes(version, attrs_data);
+        }
+
+        if version > VERSION {
+            bail!(errors::ErrorKind::VersionMismatch(VERSION, version));
+        }
+
+        // Collect entries until the end tag without interpreting them.
+        let mut attrs_data = vec![read_byte(source)?];
+        loop {
+            let tag = read_byte(source)?;
+            if tag == TAG_END {
+                break;
+            }
+
+            let mut len = [0; 2];
+            source.read_exact(&mut len)?;
+            let mut value = vec![0; u16::from_be_bytes(len) as usize];
+            source.read_exact(value.as_mut_slice())?;
+
+            attrs_data.push(tag);
+            attrs_data.extend_from_slice(&len);
+            attrs_data.extend(value);
+        }
+
+        Attributes::from_bytes(version, attrs_data)
+    }
+
+    /// Parses an attribute block for `version`. `source` must not include
+    /// the trailing `NUL` byte.
+    pub fn from_bytes(version: u8, source: Vec<u8>) -> errors::Result<Attributes> {
+        if version >= VERSION_TLV {
+            return Attributes::from_entries(version, source);
+        }
+
+        let expected: usize = Attributes::attr_block_size(version)?;
         let actual: usize = source.len();
         if actual > expected {
             bail!(errors::ErrorKind::SourceTooLarge(expected, actual));
         }
-
-        let mut nonce = [0; NONCEBYTES];
-        nonce.copy_from_slice(&source[..NONCEBYTES]);
+        if actual < expected {
+            bail!(errors::ErrorKind::CorruptHeader(expected, actual));
+        }
 
         let mut salt = [0; SALTBYTES];
-        salt.copy_from_slice(&source[NONCEBYTES..NONCEBYTES + SALTBYTES]);
+        if version == VERSION_SECRETBOX {
+            let mut nonce = [0; NONCEBYTES];
+            nonce.copy_from_slice(&source[..NONCEBYTES]);
+            salt.copy_from_slice(&source[NONCEBYTES..NONCEBYTES + SALTBYTES]);
+
+            return Ok(Attributes::new_secretbox(nonce, salt));
+        }
 
-        Ok(Attributes::new(nonce, salt))
+        salt.copy_from_slice(&source[..SALTBYTES]);
+
+        let mut attrs = Attributes::new(salt);
+        attrs.version = version;
+        Ok(attrs)
+    }
+
+    fn from_entries(version: u8, source: Vec<u8>) -> errors::Result<Attributes> {
+        if version > VERSION {
+            bail!(errors::ErrorKind::VersionMismatch(VERSION, version));
+        }
+
+        if source.is_empty() || source[0] != ATTRIBUTES_VERSION {
+            let found = source.first().cloned().unwrap_or(0);
+            bail!(errors::ErrorKind::AttributesVersionMismatch(
+                ATTRIBUTES_VERSION,
+                found
+            ));
+        }
+
+        let mut salt = None;
+        let mut kdf_algorithm = None;
+        let mut kdf_params = None;
+        let mut cipher = None;
+        let mut compression = None;
+        let mut compression_blocks = None;
+        let mut key_slots = Vec::new();
+        let mut created = None;
+        let mut original_name = None;
+
+        let mut seen = Vec::new();
+        let mut rest = &source[1..];
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                bail!(errors::ErrorKind::InvalidAttribute(rest[0]));
+            }
+
+            let tag = rest[0];
+            let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+            if rest.len() < 3 + len {
+                bail!(errors::ErrorKind::InvalidAttribute(tag));
+            }
+
+            let value = &rest[3..3 + len];
+            rest = &rest[3 + len..];
+
+            if seen.contains(&tag) {
+                bail!(errors::ErrorKind::DuplicateAttribute(tag));
+            }
+            seen.push(tag);
+
+            match tag {
+                TAG_SALT => {
+                    if value.len() != SALTBYTES {
+                        bail!(errors::ErrorKind::InvalidAttribute(tag));
+                    }
+                    let mut s = [0; SALTBYTES];
+                    s.copy_from_slice(value);
+                    salt = Some(s);
+                }
+                TAG_KDF => match KdfAlgorithm::from_id(single_byte(tag, value)?) {
+                    Some(algorithm) => kdf_algorithm = Some(algorithm),
+                    None => bail!(errors::ErrorKind::InvalidAttribute(tag)),
+                },
+                TAG_KDF_PARAMS => {
+                    if value.len() != 16 {
+                        bail!(errors::ErrorKind::InvalidAttribute(tag));
+                    }
+                    let mut ops = [0; 8];
+                    let mut mem = [0; 8];
+                    ops.copy_from_slice(&value[..8]);
+                    mem.copy_from_slice(&value[8..]);
+                    kdf_params = Some((u64::from_be_bytes(ops), u64::from_be_bytes(mem)));
+                }
+                TAG_CIPHER => match Cipher::from_id(single_byte(tag, value)?)? {
+                    // `secretbox` needs a nonce, which only version 1 headers carry
+                    Cipher::XSalsa20Poly1305 => bail!(errors::ErrorKind::InvalidAttribute(tag)),
+                    c => cipher = Some(c),
+                },
+                TAG_COMPRESSION => {
+                    compression = Some(Compression::from_id(single_byte(tag, value)?)?)
+                }
+                TAG_COMPRESSION_BLOCKS => {
+                    if value.len() != 4 {
+                        bail!(errors::ErrorKind::InvalidAttribute(tag));
+                    }
+                    let mut b = [0; 4];
+                    b.copy_from_slice(value);
+                    compression_blocks = match u32::from_be_bytes(b) {
+                        0 => bail!(errors::ErrorKind::InvalidAttribute(tag)),
+                        size => Some(size),
+                    };
+                }
+                TAG_RECIPIENTS => {
+                    if version >= VERSION_KEY_SLOTS
+                        || value.is_empty()
+                        || value.len() % WRAPPED_KEY_BYTES != 0
+                    {
+                        bail!(errors::ErrorKind::InvalidAttribute(tag));
+                    }
+                    // Version 4 tarboxes sealed for recipients list only
+                    // the wrapped keys.
+                    key_slots.extend(value.chunks(WRAPPED_KEY_BYTES).map(|w| KeySlot::Recipient {
+                        wrapped: w.to_vec(),
+                    }));
+                }
+                TAG_KEY_SLOTS if version < VERSION_KEY_SLOTS => {
+                    bail!(errors::ErrorKind::InvalidAttribute(tag))
+                }
+                TAG_KEY_SLOTS => match keyslot::from_bytes(value) {
+                    Some(ref slots) if !keyslot::within_limits(slots) => {
+                        bail!(errors::ErrorKind::InvalidAttribute(tag))
+                    }
+                    Some(slots) => key_slots.extend(slots),
+                    None => bail!(errors::ErrorKind::InvalidAttribute(tag)),
+                },
+                TAG_CREATED => {
+                    if value.len() != 8 {
+                        bail!(errors::ErrorKind::InvalidAttribute(tag));
+                    }
+                    let mut b = [0; 8];
+                    b.copy_from_slice(value);
+                    created = Some(u64::from_be_bytes(b));
+                }
+                TAG_ORIGINAL_NAME => match String::from_utf8(value.to_vec()) {
+                    Ok(name) => original_name = Some(name),
+                    Err(_) => bail!(errors::ErrorKind::InvalidAttribute(tag)),
+                },
+                _ if tag & TAG_CRITICAL != 0 => {
+                    bail!(errors::ErrorKind::UnknownCriticalAttribute(tag))
+                }
+                _ => debug!("skipping unknown optional attribute {:#04x}", tag),
+            }
+        }
+
+        // Tarboxes that predate the KDF entries were all sealed with the
+        // legacy KDF, so the pair is only required once either is present.
+        let kdf = match (kdf_algorithm, kdf_params) {
+            (None, None) => Kdf::legacy(),
+            (algorithm, params) => {
+                let algorithm = required(TAG_KDF, algorithm)?;
+                let (opslimit, memlimit) = required(TAG_KDF_PARAMS, params)?;
+                let kdf = Kdf::new(algorithm, opslimit, memlimit);
+                if !kdf.within_limits() {
+                    bail!(errors::ErrorKind::InvalidAttribute(TAG_KDF_PARAMS));
+                }
+                kdf
+            }
+        };
+
+        // The key is either derived from the secret and salt, or held in
+        // key slots from version 5 on.
+        let salt = if version < VERSION_KEY_SLOTS {
+            Some(required(TAG_SALT, salt)?)
+        } else if key_slots.is_empty() {
+            bail!(errors::ErrorKind::MissingAttribute(TAG_KEY_SLOTS))
+        } else {
+            salt
+        };
+
+        Ok(Attributes {
+            version: version,
+            nonce: None,
+            salt: salt,
+            kdf: kdf,
+            cipher: required(TAG_CIPHER, cipher)?,
+            compression: required(TAG_COMPRESSION, compression)?,
+            compression_blocks: compression_blocks,
+            key_slots: key_slots,
+            created: created,
+            original_name: original_name,
+        })
     }
 
     pub fn to_bytes(&self) -> errors::Result<Vec<u8>> {
         let mut b = Vec::new();
-        b.extend(self.nonce.into_iter());
-        b.extend(self.salt.into_iter());
+        if self.version < VERSION_TLV {
+            if let Some(ref nonce) = self.nonce {
+                b.extend(nonce.iter());
+            }
+            if let Some(ref salt) = self.salt {
+                b.extend(salt.iter());
+            }
+            return Ok(b);
+        }
+
+        b.push(ATTRIBUTES_VERSION);
+        if let Some(ref salt) = self.salt {
+            push_entry(&mut b, TAG_SALT, salt)?;
+            push_entry(&mut b, TAG_KDF, &[self.kdf.algorithm().id()])?;
+
+            let mut kdf_params = Vec::new();
+            kdf_params.extend_from_slice(&self.kdf.opslimit().to_be_bytes());
+            kdf_params.extend_from_slice(&self.kdf.memlimit().to_be_bytes());
+            push_entry(&mut b, TAG_KDF_PARAMS, kdf_params.as_slice())?;
+        }
+
+        push_entry(&mut b, TAG_CIPHER, &[self.cipher.id()])?;
+        push_entry(&mut b, TAG_COMPRESSION, &[self.compression.id()])?;
+        if let Some(block_size) = self.compression_blocks {
+            push_entry(&mut b, TAG_COMPRESSION_BLOCKS, &block_size.to_be_bytes())?;
+        }
+        if !self.key_slots.is_empty() {
+            push_entry(&mut b, TAG_KEY_SLOTS, &keyslot::to_bytes(&self.key_slots))?;
+        }
+        if let Some(created) = self.created {
+            push_entry(&mut b, TAG_CREATED, &created.to_be_bytes())?;
+        }
+        if let Some(ref name) = self.original_name {
+            push_entry(&mut b, TAG_ORIGINAL_NAME, name.as_bytes())?;
+        }
         Ok(b)
     }
 }
 
+/// Returns the part of the raw tarbox `header` that is authenticated as
+/// associated data of the encrypted stream: all of it, or everything but the
+/// key slots from version 5 on. `header` must already have been parsed.
+pub fn authenticated_header(header: &[u8]) -> errors::Result<Vec<u8>> {
+    // Magic, header version and attribute block version
+    if header.len() < 4 || header[2] < VERSION_KEY_SLOTS {
+        return Ok(header.to_vec());
+    }
+
+    splice_key_slots(header, |_| Ok(()))
+}
+
+/// Returns the raw tarbox `header` with its key slots replaced by
+/// `key_slots`. Every other byte is kept as is, so the header still
+/// authenticates against the payload. `header` must already have been
+/// parsed and be version 5 or later.
+pub fn replace_key_slots(header: &[u8], key_slots: &[KeySlot]) -> errors::Result<Vec<u8>> {
+    if header[2] < VERSION_KEY_SLOTS {
+        bail!(errors::ErrorKind::VersionMismatch(VERSION_KEY_SLOTS, header[2]));
+    }
+
+    splice_key_slots(header, |b| {
+        push_entry(b, TAG_KEY_SLOTS, &keyslot::to_bytes(key_slots))
+    })
+}
+
+/// Copies `header` entry by entry, letting `key_slots` write whatever takes
+/// the place of the key slots entry. A header with more than one key slots
+/// entry is rejected, as parsing it would be.
+fn splice_key_slots<F>(header: &[u8], mut key_slots: F) -> errors::Result<Vec<u8>>
+where
+    F: FnMut(&mut Vec<u8>) -> errors::Result<()>,
+{
+    let mut spliced = header[..4].to_vec();
+    let mut rest = &header[4..];
+    let mut replaced = false;
+    while rest.len() >= 3 {
+        let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        let entry = &rest[..cmp::min(3 + len, rest.len())];
+        if entry[0] == TAG_KEY_SLOTS {
+            if replaced {
+                bail!(errors::ErrorKind::DuplicateAttribute(TAG_KEY_SLOTS));
+            }
+            key_slots(&mut spliced)?;
+            replaced = true;
+        } else {
+            spliced.extend_from_slice(entry);
+        }
+        rest = &rest[entry.len()..];
+    }
+    spliced.extend_from_slice(rest);
+
+    Ok(spliced)
+}
+
+fn push_entry(buf: &mut Vec<u8>, tag: u8, value: &[u8]) -> errors::Result<()> {
+    if value.len() > u16::max_value() as usize {
+        bail!(errors::ErrorKind::InvalidAttribute(tag));
+    }
+
+    buf.push(tag);
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+fn single_byte(tag: u8, value: &[u8]) -> errors::Result<u8> {
+    if value.len() != 1 {
+        bail!(errors::ErrorKind::InvalidAttribute(tag));
+    }
+
+    Ok(value[0])
+}
+
+fn required<T>(tag: u8, value: Option<T>) -> errors::Result<T> {
+    match value {
+        Some(value) => Ok(value),
+        None => bail!(errors::ErrorKind::MissingAttribute(tag)),
+    }
+}
+
+fn read_byte<R: Read>(source: &mut R) -> errors::Result<u8> {
+    let mut byte = [0; 1];
+    source.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
 impl From<TarboxSecret> for Attributes {
     fn from(s: TarboxSecret) -> Self {
-        Attributes {
-            nonce: s.nonce().0,
-            salt: s.salt().0,
-        }
+        Attributes::from(&s)
     }
 }
 
 impl<'a> From<&'a TarboxSecret> for Attributes {
     fn from(s: &'a TarboxSecret) -> Self {
-        Attributes {
-            nonce: s.nonce().0.clone(),
-            salt: s.salt().0.clone(),
-        }
+        let mut attrs = Attributes::new(s.salt().0.clone());
+        attrs.set_kdf(*s.kdf());
+        attrs
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sodiumoxide::crypto::{secretbox, secretstream};
+    use tarbox::secret::{Factors, KdfCost};
 
     fn make_data() -> (NonceBytes, SaltBytes) {
         let nonce = [0xbe; NONCEBYTES];
@@ -109,15 +749,16 @@ mod tests {
         let (nonce, salt) = make_data();
         let source = make_source(nonce, salt);
 
-        let attrs = Attributes::from_bytes(source).unwrap();
-        assert_eq!(attrs.nonce, nonce);
-        assert_eq!(attrs.salt, salt);
+        let attrs = Attributes::from_bytes(VERSION_SECRETBOX, source).unwrap();
+        assert_eq!(attrs.version(), VERSION_SECRETBOX);
+        assert_eq!(attrs.nonce, Some(nonce));
+        assert_eq!(attrs.salt, Some(salt));
     }
 
     #[test]
     fn test_to_bytes() {
         let (nonce, salt) = make_data();
-        let attrs = Attributes::new(nonce, salt);
+        let attrs = Attributes::new_secretbox(nonce, salt);
 
         let mut expected = Vec::new();
         expected.extend_from_slice(&nonce);
@@ -127,13 +768,378 @@ mod tests {
         assert_eq!(encoded, expected);
     }
 
+    #[test]
+    fn test_encoded_values() {
+        let (nonce, salt) = make_data();
+        let attrs = Attributes::new_secretbox(nonce, salt);
+        assert_eq!(attrs.encoded_nonce(), Some(base64::encode(&nonce[..])));
+        assert_eq!(attrs.encoded_salt(), Some(base64::encode(&salt[..])));
+        assert_eq!(Attributes::new(salt).encoded_nonce(), None);
+    }
+
+    #[test]
+    fn test_secretstream_from_bytes() {
+        let (_, salt) = make_data();
+
+        let decoded = Attributes::from_bytes(VERSION_SECRETSTREAM, salt.to_vec()).unwrap();
+        assert_eq!(decoded.version(), VERSION_SECRETSTREAM);
+        assert_eq!(decoded.cipher(), Cipher::XChaCha20Poly1305);
+        assert_eq!(decoded.nonce(), None);
+        assert_eq!(decoded.salt(), Some(&salt));
+    }
+
+    #[test]
+    fn test_entries_roundtrip() {
+        let (_, salt) = make_data();
+        let mut attrs = Attributes::new(salt);
+        attrs.set_created(1_534_000_000);
+        attrs.set_original_name(String::from("configs"));
+
+        let mut encoded = attrs.to_bytes().unwrap();
+        encoded.push(TAG_END);
+
+        let decoded = Attributes::read_from(VERSION_TLV, &mut encoded.as_slice()).unwrap();
+        assert_eq!(decoded.version(), VERSION_TLV);
+        assert_eq!(decoded.salt(), Some(&salt));
+        assert_eq!(decoded.cipher(), Cipher::XChaCha20Poly1305);
+        assert_eq!(decoded.compression(), Compression::Gzip);
+        assert_eq!(decoded.created(), Some(1_534_000_000));
+        assert_eq!(decoded.original_name(), Some(&String::from("configs")));
+    }
+
+    #[test]
+    fn test_entries_compression() {
+        let (_, salt) = make_data();
+        for compression in [Compression::Zstd, Compression::Xz, Compression::None].iter() {
+            let mut attrs = Attributes::new(salt);
+            attrs.set_compression(*compression);
+            attrs.set_compression_blocks(4_194_304);
+
+            let encoded = attrs.to_bytes().unwrap();
+            let decoded = Attributes::from_bytes(VERSION_TLV, encoded).unwrap();
+            assert_eq!(decoded.compression(), *compression);
+            assert_eq!(decoded.compression_blocks(), Some(4_194_304));
+        }
+
+        let mut encoded = Attributes::new(salt).to_bytes().unwrap();
+        // Skip the attributes version, then walk the entries to the tag.
+        let mut pos = 1;
+        while encoded[pos] != TAG_COMPRESSION {
+            pos += 3 + u16::from_be_bytes([encoded[pos + 1], encoded[pos + 2]]) as usize;
+        }
+        encoded[pos + 3] = 0x7f;
+        let res = Attributes::from_bytes(VERSION_TLV, encoded);
+        if let Err(errors::Error(errors::ErrorKind::InvalidAttribute(tag), _)) = res {
+            assert_eq!(TAG_COMPRESSION, tag);
+        } else {
+            panic!(format!("expected `InvalidAttribute` error, got: {:?}", res));
+        }
+    }
+
+    #[test]
+    fn test_entries_recipients() {
+        let (_, salt) = make_data();
+        let mut encoded = Attributes::new(salt).to_bytes().unwrap();
+        encoded.extend_from_slice(&[TAG_RECIPIENTS, 0x0, 2 * WRAPPED_KEY_BYTES as u8]);
+        encoded.extend_from_slice(&[0x1; WRAPPED_KEY_BYTES]);
+        encoded.extend_from_slice(&[0x2; WRAPPED_KEY_BYTES]);
+
+        let decoded = Attributes::from_bytes(VERSION_AUTHENTICATED, encoded).unwrap();
+        assert_eq!(
+            decoded.key_slots(),
+            &[
+                KeySlot::Recipient {
+                    wrapped: vec![0x1; WRAPPED_KEY_BYTES]
+                },
+                KeySlot::Recipient {
+                    wrapped: vec![0x2; WRAPPED_KEY_BYTES]
+                },
+            ]
+        );
+
+        let mut encoded = Attributes::new(salt).to_bytes().unwrap();
+        encoded.extend_from_slice(&[TAG_RECIPIENTS, 0x0, 0x1, 0x0]);
+        let res = Attributes::from_bytes(VERSION_TLV, encoded);
+        if let Err(errors::Error(errors::ErrorKind::InvalidAttribute(tag), _)) = res {
+            assert_eq!(TAG_RECIPIENTS, tag);
+        } else {
+            panic!(format!("expected `InvalidAttribute` error, got: {:?}", res));
+        }
+    }
+
+    #[test]
+    fn test_entries_key_slots() {
+        let slots = vec![KeySlot::Recipient {
+            wrapped: vec![0x1; WRAPPED_KEY_BYTES],
+        }];
+        let mut attrs = Attributes::with_key_slots(slots.clone());
+        attrs.set_created(1_534_000_000);
+
+        let encoded = attrs.to_bytes().unwrap();
+        let decoded = Attributes::from_bytes(VERSION_KEY_SLOTS, encoded).unwrap();
+        assert_eq!(decoded.key_slots(), slots.as_slice());
+        assert_eq!(decoded.salt(), None);
+        assert_eq!(decoded.created(), Some(1_534_000_000));
+
+        let encoded = Attributes::with_key_slots(Vec::new()).to_bytes().unwrap();
+        let res = Attributes::from_bytes(VERSION_KEY_SLOTS, encoded);
+        if let Err(errors::Error(errors::ErrorKind::MissingAttribute(tag), _)) = res {
+            assert_eq!(TAG_KEY_SLOTS, tag);
+        } else {
+            panic!(format!("expected `MissingAttribute` error, got: {:?}", res));
+        }
+    }
+
+    #[test]
+    fn test_entries_key_slots_limits() {
+        let recipient = KeySlot::Recipient {
+            wrapped: vec![0x1; WRAPPED_KEY_BYTES],
+        };
+        let secret = |kdf: Kdf| KeySlot::Secret {
+            factors: Factors::Secret,
+            kdf: kdf,
+            salt: [0x2; SALTBYTES],
+            nonce: [0x3; NONCEBYTES],
+            wrapped: vec![0x4; secretstream::KEYBYTES + secretbox::MACBYTES],
+        };
+        let decode = |slots: Vec<KeySlot>| {
+            let encoded = Attributes::with_key_slots(slots).to_bytes().unwrap();
+            Attributes::from_bytes(VERSION_KEY_SLOTS, encoded)
+        };
+        let expect_invalid = |res: errors::Result<Attributes>, expected: u8| {
+            if let Err(errors::Error(errors::ErrorKind::InvalidAttribute(tag), _)) = res {
+                assert_eq!(expected, tag);
+            } else {
+                panic!(format!("expected `InvalidAttribute` error, got: {:?}", res));
+            }
+        };
+
+        assert!(decode(vec![recipient.clone(); keyslot::MAX_KEY_SLOTS]).is_ok());
+        expect_invalid(
+            decode(vec![recipient.clone(); keyslot::MAX_KEY_SLOTS + 1]),
+            TAG_KEY_SLOTS,
+        );
+
+        let sensitive = Kdf::preset(KdfAlgorithm::Argon2id13, KdfCost::Sensitive).unwrap();
+        let affordable = (keyslot::MAX_KDF_COST / sensitive.cost()) as usize;
+        assert!(decode(vec![secret(sensitive); affordable]).is_ok());
+        expect_invalid(
+            decode(vec![secret(sensitive); affordable + 1]),
+            TAG_KEY_SLOTS,
+        );
+
+        // A second list of slots must not slip past the limits
+        let one_slot = keyslot::to_bytes(&[recipient.clone()]);
+        let mut encoded = Attributes::with_key_slots(vec![recipient.clone()])
+            .to_bytes()
+            .unwrap();
+        push_entry(&mut encoded, TAG_KEY_SLOTS, &one_slot).unwrap();
+        let res = Attributes::from_bytes(VERSION_KEY_SLOTS, encoded);
+        if let Err(errors::Error(errors::ErrorKind::DuplicateAttribute(tag), _)) = res {
+            assert_eq!(TAG_KEY_SLOTS, tag);
+        } else {
+            panic!(format!("expected `DuplicateAttribute` error, got: {:?}", res));
+        }
+
+        // Nor may version 4 recipients, which carry no slot types
+        let mut encoded = Attributes::with_key_slots(vec![recipient.clone()])
+            .to_bytes()
+            .unwrap();
+        push_entry(&mut encoded, TAG_RECIPIENTS, &[0x1; WRAPPED_KEY_BYTES]).unwrap();
+        expect_invalid(
+            Attributes::from_bytes(VERSION_KEY_SLOTS, encoded),
+            TAG_RECIPIENTS,
+        );
+
+        let mut encoded = Attributes::new([0x5a; SALTBYTES]).to_bytes().unwrap();
+        push_entry(&mut encoded, TAG_KEY_SLOTS, &one_slot).unwrap();
+        expect_invalid(
+            Attributes::from_bytes(VERSION_AUTHENTICATED, encoded),
+            TAG_KEY_SLOTS,
+        );
+    }
+
+    #[test]
+    fn test_authenticated_header() {
+        let slots = vec![KeySlot::Recipient {
+            wrapped: vec![0x1; WRAPPED_KEY_BYTES],
+        }];
+        let mut attrs = Attributes::with_key_slots(slots);
+        attrs.set_original_name(String::from("configs"));
+
+        let header = |attrs: &Attributes| {
+            let mut header = vec![0x7a, 0xb0, attrs.version()];
+            header.extend(attrs.to_bytes().unwrap());
+            header.push(TAG_END);
+            header
+        };
+
+        let mut rekeyed = attrs.clone();
+        rekeyed.set_key_slots(vec![KeySlot::Recipient {
+            wrapped: vec![0x2; WRAPPED_KEY_BYTES],
+        }]);
+        assert_ne!(header(&attrs), header(&rekeyed));
+        assert_eq!(
+            authenticated_header(&header(&attrs)).unwrap(),
+            authenticated_header(&header(&rekeyed)).unwrap()
+        );
+
+        let mut renamed = attrs.clone();
+        renamed.set_original_name(String::from("secrets"));
+        assert_ne!(
+            authenticated_header(&header(&attrs)).unwrap(),
+            authenticated_header(&header(&renamed)).unwrap()
+        );
+
+        let legacy = header(&Attributes::new([0x5a; SALTBYTES]));
+        assert_eq!(legacy, authenticated_header(&legacy).unwrap());
+    }
+
+    #[test]
+    fn test_replace_key_slots() {
+        let mut attrs = Attributes::with_key_slots(vec![KeySlot::Recipient {
+            wrapped: vec![0x1; WRAPPED_KEY_BYTES],
+        }]);
+        attrs.set_created(1_534_000_000);
+
+        let mut header = vec![0x7a, 0xb0, attrs.version()];
+        header.extend(attrs.to_bytes().unwrap());
+        // Unknown optional entries must survive too
+        header.extend_from_slice(&[0x7f, 0x0, 0x1, 0xaa]);
+        header.push(TAG_END);
+
+        let slots = vec![
+            KeySlot::Recipient {
+                wrapped: vec![0x2; WRAPPED_KEY_BYTES],
+            },
+            KeySlot::Recipient {
+                wrapped: vec![0x3; WRAPPED_KEY_BYTES],
+            },
+        ];
+        let replaced = replace_key_slots(&header, &slots).unwrap();
+        assert_eq!(
+            authenticated_header(&header).unwrap(),
+            authenticated_header(&replaced).unwrap()
+        );
+
+        let decoded = Attributes::read_from(VERSION_KEY_SLOTS, &mut &replaced[3..]).unwrap();
+        assert_eq!(decoded.key_slots(), slots.as_slice());
+        assert_eq!(decoded.created(), Some(1_534_000_000));
+
+        let mut legacy = vec![0x7a, 0xb0, VERSION_AUTHENTICATED];
+        legacy.extend(Attributes::new([0x5a; SALTBYTES]).to_bytes().unwrap());
+        legacy.push(TAG_END);
+        assert!(replace_key_slots(&legacy, &slots).is_err());
+
+        // A second key slots entry would be left out of what is
+        // authenticated, so it must not be passed through or replaced.
+        let mut doubled = header[..header.len() - 1].to_vec();
+        push_entry(&mut doubled, TAG_KEY_SLOTS, &keyslot::to_bytes(&slots)).unwrap();
+        doubled.push(TAG_END);
+        let results = vec![
+            authenticated_header(&doubled),
+            replace_key_slots(&doubled, &slots),
+        ];
+        for res in results {
+            if let Err(errors::Error(errors::ErrorKind::DuplicateAttribute(tag), _)) = res {
+                assert_eq!(TAG_KEY_SLOTS, tag);
+            } else {
+                panic!(format!("expected `DuplicateAttribute` error, got: {:?}", res));
+            }
+        }
+    }
+
+    #[test]
+    fn test_entries_kdf() {
+        let (_, salt) = make_data();
+        let kdf = Kdf::new(KdfAlgorithm::ScryptSalsa208Sha256, 1_048_576, 33_554_432);
+        let mut attrs = Attributes::new(salt);
+        attrs.set_kdf(kdf);
+
+        let encoded = attrs.to_bytes().unwrap();
+        let decoded = Attributes::from_bytes(VERSION_TLV, encoded).unwrap();
+        assert_eq!(decoded.kdf(), &kdf);
+    }
+
+    #[test]
+    fn test_entries_kdf_defaults_to_legacy() {
+        let (_, salt) = make_data();
+        let mut encoded = vec![ATTRIBUTES_VERSION];
+        encoded.extend_from_slice(&[TAG_SALT, 0x0, SALTBYTES as u8]);
+        encoded.extend_from_slice(&salt);
+        encoded.extend_from_slice(&[TAG_CIPHER, 0x0, 0x1, 0x2]);
+        encoded.extend_from_slice(&[TAG_COMPRESSION, 0x0, 0x1, 0x1]);
+
+        let decoded = Attributes::from_bytes(VERSION_TLV, encoded).unwrap();
+        assert_eq!(decoded.kdf(), &Kdf::legacy());
+    }
+
+    #[test]
+    fn test_entries_kdf_over_limits() {
+        let (_, salt) = make_data();
+        let mut attrs = Attributes::new(salt);
+        attrs.set_kdf(Kdf::new(
+            KdfAlgorithm::ScryptSalsa208Sha256,
+            u64::max_value(),
+            u64::max_value(),
+        ));
+
+        let encoded = attrs.to_bytes().unwrap();
+        let res = Attributes::from_bytes(VERSION_TLV, encoded);
+        if let Err(errors::Error(errors::ErrorKind::InvalidAttribute(tag), _)) = res {
+            assert_eq!(TAG_KDF_PARAMS, tag);
+        } else {
+            panic!(format!("expected `InvalidAttribute` error, got: {:?}", res));
+        }
+    }
+
+    #[test]
+    fn test_entries_skip_unknown_optional() {
+        let (_, salt) = make_data();
+        let mut encoded = Attributes::new(salt).to_bytes().unwrap();
+        encoded.extend_from_slice(&[0x7f, 0x0, 0x2, 0x0, 0x0]);
+
+        let decoded = Attributes::from_bytes(VERSION_TLV, encoded).unwrap();
+        assert_eq!(decoded.salt(), Some(&salt));
+    }
+
+    #[test]
+    fn test_entries_reject_unknown_critical() {
+        let (_, salt) = make_data();
+        let mut encoded = Attributes::new(salt).to_bytes().unwrap();
+        encoded.extend_from_slice(&[0xff, 0x0, 0x1, 0x0]);
+
+        let res = Attributes::from_bytes(VERSION_TLV, encoded);
+        if let Err(errors::Error(errors::ErrorKind::UnknownCriticalAttribute(tag), _)) = res {
+            assert_eq!(0xff, tag);
+        } else {
+            panic!(format!(
+                "expected `UnknownCriticalAttribute` error, got: {:?}",
+                res
+            ));
+        }
+    }
+
+    #[test]
+    fn test_entries_missing_required() {
+        let mut encoded = vec![ATTRIBUTES_VERSION];
+        encoded.extend_from_slice(&[TAG_CIPHER, 0x0, 0x1, 0x2]);
+
+        let res = Attributes::from_bytes(VERSION_TLV, encoded);
+        if let Err(errors::Error(errors::ErrorKind::MissingAttribute(tag), _)) = res {
+            assert_eq!(TAG_SALT, tag);
+        } else {
+            panic!(format!("expected `MissingAttribute` error, got: {:?}", res));
+        }
+    }
+
     #[test]
     fn test_source_unconsumed() {
         let (nonce, salt) = make_data();
         let mut source = make_source(nonce, salt);
         source.extend_from_slice(&[0xca, 0xfe]);
 
-        let res = Attributes::from_bytes(source);
+        let res = Attributes::from_bytes(VERSION_SECRETBOX, source);
         assert!(res.is_err());
         let err = res.unwrap_err();
         if let errors::Error(errors::ErrorKind::SourceTooLarge(_, actual), _) = err {
@@ -148,4 +1154,20 @@ mod tests {
             ));
         }
     }
+
+    #[test]
+    fn test_source_truncated() {
+        let (nonce, salt) = make_data();
+        let source = make_source(nonce, salt);
+
+        for &(version, len) in &[(VERSION_SECRETBOX, 40), (VERSION_SECRETSTREAM, 16)] {
+            let res = Attributes::from_bytes(version, source[..len].to_vec());
+            match res {
+                Err(errors::Error(errors::ErrorKind::CorruptHeader(_, actual), _)) => {
+                    assert_eq!(len, actual)
+                }
+                res => panic!(format!("expected `CorruptHeader` error, got: {:?}", res)),
+            }
+        }
+    }
 }
@@ -1,58 +1,52 @@
-use std::cmp;
 use std::io;
-use std::io::{Read, Write};
+use std::io::Read;
 
-use super::{attributes::Attributes, errors, TARBOX_MAGIC};
+use super::{attributes, attributes::Attributes, errors, TARBOX_MAGIC};
 
-#[derive(Clone, Debug)]
-pub struct Decoder {
-    inner: Vec<u8>,
+#[derive(Debug)]
+pub struct Decoder<R: Read> {
+    inner: R,
     attrs: Attributes,
+    header: Vec<u8>,
 }
 
-impl Decoder {
-    pub fn new(mut inner: Vec<u8>) -> errors::Result<Decoder> {
-        // Before we can unwrap the stream to the data chunk,
-        // we need to read the magic bytes off the front and
-        // determine which header version was in use.
-
-        // Read and check the magic bytes
-        let prelude: Vec<_> = inner.drain(..2).collect();
-        let prelude = prelude.as_slice();
-        if prelude != TARBOX_MAGIC {
-            let mut actual: [u8; 2] = Default::default();
-            actual.copy_from_slice(&prelude);
-            bail!(errors::ErrorKind::HeaderMismatch(TARBOX_MAGIC, actual));
-        }
-
-        // Get the version byte
-        let version = inner.remove(0);
-        if version != Attributes::version() {
-            bail!(errors::ErrorKind::VersionMismatch(
-                Attributes::version(),
-                version
-            ));
-        }
-
-        // Now that we have verified the version of the header,
-        // read all bytes until a `NUL` is encountered.
-        let attrs_data: Vec<_> = inner.drain(..Attributes::attr_block_size()).collect();
-
-        let attrs = Attributes::from_bytes(attrs_data)?;
-
-        // The next byte we read should be a `NUL`.
-        let next = inner.remove(0);
-        if next != 0x0 {
-            bail!(errors::ErrorKind::ExpectedNullByte(next));
-        }
-
-        debug!("unwrapped inner data length: {}", inner.len());
-
-        // The remaining data in `inner` is the encrypted archive.
-        // Move it into the `Decoder` instance along with `attrs`.
+impl<R: Read> Decoder<R> {
+    pub fn new(mut inner: R) -> errors::Result<Decoder<R>> {
+        let (attrs, header) = {
+            // Keep a copy of every header byte read so it can later be
+            // authenticated exactly as it appears in the tarbox.
+            let mut source = HeaderReader {
+                inner: &mut inner,
+                header: Vec::new(),
+            };
+
+            // Before we can unwrap the stream to the data chunk,
+            // we need to read the magic bytes off the front and
+            // determine which header version was in use.
+
+            // Read and check the magic bytes
+            let mut prelude: [u8; 2] = Default::default();
+            source.read_exact(&mut prelude)?;
+            if prelude != TARBOX_MAGIC {
+                bail!(errors::ErrorKind::HeaderMismatch(TARBOX_MAGIC, prelude));
+            }
+
+            // Get the version byte, which determines the layout of the
+            // attribute block. Unknown versions are rejected here.
+            let version = read_byte(&mut source)?;
+
+            // Read the attribute block along with the `NUL` that ends the header.
+            let attrs = Attributes::read_from(version, &mut source)?;
+
+            (attrs, source.header)
+        };
+
+        // Everything left in `inner` is the encrypted archive, which
+        // is handed out as-is through `Read`.
         Ok(Decoder {
             inner: inner,
             attrs: attrs,
+            header: header,
         })
     }
 
@@ -63,66 +57,72 @@ impl Decoder {
     pub fn attributes_into(self) -> Attributes {
         self.attrs
     }
-}
 
-impl Read for Decoder {
-    // Reads bytes out of the inner container into an output buffer.
-    fn read(&mut self, mut outbuf: &mut [u8]) -> io::Result<usize> {
-        let buffer_size = outbuf.len();
-        let inner_size = self.inner.len();
-        debug!("{} bytes remaining in inner", inner_size);
+    /// The raw header bytes, from the magic through the `NUL`.
+    pub fn header(&self) -> &[u8] {
+        self.header.as_slice()
+    }
 
-        let drain_size = cmp::min(inner_size, buffer_size);
-        debug!("draining {} bytes from inner into outbuf", drain_size);
+    /// The part of the header that the encrypted stream authenticates.
+    pub fn authenticated_header(&self) -> errors::Result<Vec<u8>> {
+        attributes::authenticated_header(&self.header)
+    }
+}
 
-        let drain = self.inner.drain(..drain_size);
-        debug!("drain currently holding {} bytes", drain.len());
+impl<R: Read> Read for Decoder<R> {
+    // Reads bytes out of the inner container into an output buffer.
+    fn read(&mut self, outbuf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(outbuf)
+    }
+}
 
-        let written = outbuf.write(&drain.collect::<Vec<_>>())?;
-        debug!("wrote {} bytes to outbuf", written);
+/// Records everything read through it.
+struct HeaderReader<'a, R: Read + 'a> {
+    inner: &'a mut R,
+    header: Vec<u8>,
+}
 
-        Ok(written)
+impl<'a, R: Read> Read for HeaderReader<'a, R> {
+    fn read(&mut self, outbuf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(outbuf)?;
+        self.header.extend_from_slice(&outbuf[..read]);
+        Ok(read)
     }
 }
 
+fn read_byte<R: Read>(inner: &mut R) -> io::Result<u8> {
+    let mut byte = [0; 1];
+    inner.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
 #[cfg(test)]
 mod tests {
     use super::{errors, Decoder, TARBOX_MAGIC};
-    use password;
     use std::io::Read;
-    use tarbox::secret::{Nonce, Salt, TarboxSecret, TarboxSecretBuilder, NONCEBYTES, SALTBYTES};
-
-    fn make_tarbox_secret() -> TarboxSecret {
-        let nonce = Nonce::from_slice(&[0xfe; NONCEBYTES]).unwrap();
-        let salt = Salt::from_slice(&[0xba; SALTBYTES]).unwrap();
-        TarboxSecretBuilder::new()
-            .password(password::generate_password())
-            .nonce(nonce)
-            .salt(salt)
-            .build()
-            .unwrap()
-    }
+    use tarbox::attributes::{Attributes, VERSION_SECRETBOX, VERSION_SECRETSTREAM, VERSION_TLV};
+    use tarbox::secret::{NONCEBYTES, SALTBYTES};
 
-    fn make_header(secret: &TarboxSecret) -> Vec<u8> {
+    fn make_header(version: u8) -> Vec<u8> {
         let mut payload: Vec<u8> = Vec::new();
         payload.extend(&TARBOX_MAGIC);
-        payload.push(0x1);
-        payload.extend(secret.nonce().0.into_iter());
-        payload.extend(secret.salt().0.into_iter());
+        payload.push(version);
+        if version == VERSION_SECRETBOX {
+            payload.extend(&[0xfe; NONCEBYTES]);
+        }
+        payload.extend(&[0xba; SALTBYTES]);
         payload.push(0x0); // NUL -- end of header
         payload
     }
 
     #[test]
     fn test_decoder() {
-        let secret = make_tarbox_secret();
-
         // Manually construct a payload to decode
-        let mut payload = make_header(&secret);
+        let mut payload = make_header(VERSION_SECRETBOX);
         payload.extend(&[0xfa, 0xce]);
 
         // Create a decoder and read the inner data from it
-        let mut dec = Decoder::new(payload).unwrap();
+        let mut dec = Decoder::new(payload.as_slice()).unwrap();
         let mut data = Vec::new();
         let actual_size = dec
             .read_to_end(&mut data)
@@ -131,21 +131,78 @@ mod tests {
 
         assert_eq!(2, actual_size);
         assert_eq!(data.as_slice(), &[0xfa, 0xce]);
-        assert_eq!(attrs.nonce(), &[0xfe; NONCEBYTES]);
-        assert_eq!(attrs.salt(), &[0xba; SALTBYTES]);
+        assert_eq!(attrs.nonce(), Some(&[0xfe; NONCEBYTES]));
+        assert_eq!(attrs.salt(), Some(&[0xba; SALTBYTES]));
     }
 
     #[test]
-    fn test_decode_missing_header_delimiter() {
-        let secret = make_tarbox_secret();
+    fn test_decoder_secretstream() {
+        let mut payload = make_header(VERSION_SECRETSTREAM);
+        payload.extend(&[0xfa, 0xce]);
+
+        let mut dec = Decoder::new(payload.as_slice()).unwrap();
+        let mut data = Vec::new();
+        dec.read_to_end(&mut data)
+            .expect("error reading data into decoder");
+        let attrs = dec.attributes_into();
+
+        assert_eq!(data.as_slice(), &[0xfa, 0xce]);
+        assert_eq!(attrs.version(), VERSION_SECRETSTREAM);
+        assert_eq!(attrs.nonce(), None);
+        assert_eq!(attrs.salt(), Some(&[0xba; SALTBYTES]));
+    }
+
+    #[test]
+    fn test_decoder_entries() {
+        let mut attrs = Attributes::new([0xba; SALTBYTES]);
+        attrs.set_original_name(String::from("test.txt"));
+
+        let mut payload = Vec::new();
+        payload.extend(&TARBOX_MAGIC);
+        payload.push(VERSION_TLV);
+        payload.extend(attrs.to_bytes().unwrap());
+        payload.push(0x0); // NUL -- end of header
+        payload.extend(&[0xfa, 0xce]);
+
+        let mut dec = Decoder::new(payload.as_slice()).unwrap();
+        let mut data = Vec::new();
+        dec.read_to_end(&mut data)
+            .expect("error reading data into decoder");
+
+        assert_eq!(data.as_slice(), &[0xfa, 0xce]);
+        assert_eq!(dec.header(), &payload[..payload.len() - 2]);
 
+        let attrs = dec.attributes_into();
+        assert_eq!(attrs.version(), VERSION_TLV);
+        assert_eq!(attrs.salt(), Some(&[0xba; SALTBYTES]));
+        assert_eq!(attrs.original_name(), Some(&String::from("test.txt")));
+    }
+
+    #[test]
+    fn test_decode_unknown_version() {
+        let mut payload = make_header(VERSION_SECRETSTREAM);
+        payload[2] = 0xff;
+
+        let res = Decoder::new(payload.as_slice());
+
+        assert!(res.is_err());
+        let err = res.unwrap_err();
+        if let errors::Error(errors::ErrorKind::VersionMismatch(_, actual), _) = err {
+            assert_eq!(0xff, actual);
+        } else {
+            panic!(format!("expected `VersionMismatch` error, got: {:?}", err));
+        }
+    }
+
+    #[test]
+    fn test_decode_missing_header_delimiter() {
         // Manually construct a payload to decode
-        let mut payload = make_header(&secret);
+        let mut payload = make_header(VERSION_SECRETBOX);
         assert_eq!(payload.pop().unwrap(), 0x0);
         payload.extend(&[0xfa, 0xce]);
 
         // Create a decoder and read the inner data from it
-        let res = Decoder::new(payload);
+        let res = Decoder::new(payload.as_slice());
 
         assert!(res.is_err());
         let err = res.unwrap_err();
@@ -1,29 +1,19 @@
 use std::io;
 use std::io::Write;
 
-use super::{Attributes, TARBOX_MAGIC};
+use super::{attributes, errors, Attributes, TARBOX_MAGIC};
 
-#[derive(Clone, Debug)]
-pub struct Encoder {
-    inner: Vec<u8>,
+#[derive(Debug)]
+pub struct Encoder<W: Write> {
+    inner: W,
     attributes: Attributes,
+    header: Vec<u8>,
 }
 
-impl Encoder {
-    /// Returns a new Encoder including a new, empty `AttributesV1` instance.
-    pub fn new(attrs: Attributes) -> Encoder {
-        Encoder {
-            inner: Vec::new(),
-            attributes: attrs,
-        }
-    }
-
-    pub fn attributes(self) -> Attributes {
-        self.attributes
-    }
-
-    /// Writes the tarbox header and then the wrapped content.
-    /// The header will look like the following:
+impl<W: Write> Encoder<W> {
+    /// Writes the tarbox header to `inner` and returns an `Encoder`
+    /// that passes every subsequent write straight through as the
+    /// wrapped content. The header will look like the following:
     ///
     /// ```text
     /// +--------------+--------+-------+-----+
@@ -32,35 +22,57 @@ impl Encoder {
     /// |    [u8; 2]   |   u8   | [u8]  | u8  |
     /// +--------------+--------+-------+-----+
     /// ```
-    pub fn finish(self) -> Vec<u8> {
-        let mut final_buf = Vec::new();
-        final_buf.extend_from_slice(&TARBOX_MAGIC);
+    pub fn new(mut inner: W, attrs: Attributes) -> errors::Result<Encoder<W>> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&TARBOX_MAGIC);
 
         // Push header version
-        let attrs_version = Attributes::version();
-        final_buf.push(attrs_version);
+        header.push(attrs.version());
 
-        // Push attributes including version header
-        let attrs_data = self.attributes.to_bytes().unwrap();
-        final_buf.extend(attrs_data.iter());
+        // Push attributes
+        let attrs_data = attrs.to_bytes()?;
+        header.extend(attrs_data.iter());
 
         // Push the end of header byte
-        final_buf.push(0);
+        header.push(0);
+
+        inner.write_all(header.as_slice())?;
+
+        Ok(Encoder {
+            inner: inner,
+            attributes: attrs,
+            header: header,
+        })
+    }
+
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    /// The raw header bytes written ahead of the wrapped content.
+    pub fn header(&self) -> &[u8] {
+        self.header.as_slice()
+    }
+
+    /// The part of the header that the encrypted stream authenticates.
+    pub fn authenticated_header(&self) -> errors::Result<Vec<u8>> {
+        attributes::authenticated_header(&self.header)
+    }
 
-        final_buf.extend(self.inner.into_iter());
-        return final_buf;
+    /// Flushes the wrapped content and hands back the inner writer.
+    pub fn finish(mut self) -> errors::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
     }
 }
 
-impl Write for Encoder {
+impl<W: Write> Write for Encoder<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let bytes_len = buf.len();
-        self.inner.extend(buf);
-        Ok(bytes_len)
+        self.inner.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        self.inner.flush()
     }
 }
 
@@ -72,13 +84,14 @@ mod tests {
     #[test]
     fn test_encoder() {
         let attrs = Attributes::empty();
-        let mut enc = Encoder::new(attrs.clone());
+        let mut enc = Encoder::new(Vec::new(), attrs.clone()).unwrap();
 
         let inner: [u8; 2] = [0xca, 0xfe];
         assert_eq!(2, enc.write(&inner).unwrap());
 
-        let version = Attributes::version();
-        let data = enc.finish();
+        let version = attrs.version();
+        let header = enc.header().to_vec();
+        let data = enc.finish().unwrap();
 
         let mut expected_payload = Vec::new();
         expected_payload.extend(&TARBOX_MAGIC);
@@ -88,5 +101,6 @@ mod tests {
         let expected_payload = expected_payload.as_slice();
 
         assert_eq!(expected_payload, data.as_slice());
+        assert_eq!(&expected_payload[..expected_payload.len() - 2], header.as_slice());
     }
 }
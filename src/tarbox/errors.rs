@@ -5,6 +5,26 @@ error_chain! {
     }
 
     errors {
+        AttributesVersionMismatch(expected: u8, actual: u8) {
+            description("tarbox attribute block version mismatch"),
+            display("tarbox attribute block version mismatch: expected={} actual={}", expected, actual),
+        }
+
+        CorruptHeader(expected: usize, actual: usize) {
+            description("tarbox header is truncated"),
+            display("tarbox header is truncated: expected {} bytes, found {}", expected, actual),
+        }
+
+        DuplicateAttribute(tag: u8) {
+            description("tarbox attribute appears more than once"),
+            display("tarbox attribute appears more than once: {:#04x}", tag),
+        }
+
+        EmptyKeyfile(path: String) {
+            description("keyfile is empty"),
+            display("keyfile is empty: {}", path),
+        }
+
         ExpectedNullByte(found: u8) {
             description("expected a null byte"),
             display("expected a null byte, found: {:?}", found),
@@ -15,16 +35,51 @@ error_chain! {
             display("input header {:?} did not match expected {:?}", actual, expected),
         }
 
+        InvalidAttribute(tag: u8) {
+            description("tarbox attribute is malformed"),
+            display("tarbox attribute is malformed: {:#04x}", tag),
+        }
+
+        InvalidIdentity(path: String) {
+            description("identity file does not hold a secret key"),
+            display("identity file does not hold a secret key: {}", path),
+        }
+
         InvalidKeyData(kd: String) {
             description("the given key data could not be parsed by <key>.<nonce> format"),
             display("invalid key data: {}", kd),
         }
 
+        InvalidPublicKey(key: String) {
+            description("recipient public key is malformed"),
+            display("recipient public key is malformed: {}", key),
+        }
+
+        KdfPresetUnavailable(algorithm: String, cost: String) {
+            description("kdf has no such cost preset"),
+            display("kdf {} has no {} cost preset", algorithm, cost),
+        }
+
+        KeyDerivationFail {
+            description("could not derive key from secret"),
+            display("could not derive key from secret"),
+        }
+
+        MissingAttribute(tag: u8) {
+            description("required tarbox attribute is missing"),
+            display("required tarbox attribute is missing: {:#04x}", tag),
+        }
+
         SourceTooLarge(expected: usize, actual: usize) {
             description("source vector is too large"),
             display("source vector is too large: {} expected < {} actual", expected, actual),
         }
 
+        UnknownCriticalAttribute(tag: u8) {
+            description("tarbox has a critical attribute this version does not understand"),
+            display("tarbox has a critical attribute this version does not understand: {:#04x}", tag),
+        }
+
         VersionMismatch(expected: u8, actual: u8) {
             description("tarbox header version mismatch"),
             display("tarbox header version mismatch: expected={} actual={}", expected, actual),
@@ -0,0 +1,332 @@
+//! Key slots.
+//!
+//! From version 5 on, the payload of a tarbox is encrypted with a random
+//! data key. The header holds one or more key slots, each wrapping the data
+//! key for a different secret or recipient, so any one of them opens the
+//! tarbox.
+//!
+//! Each slot is written as its type, the length of its body and the body:
+//!
+//! ```text
+//! +--------+--------+--------+-----+
+//! |  TYPE  |  LEN   |  BODY  | ... |
+//! +--------+--------+--------+-----+
+//! |   u8   | u16 BE |  [u8]  |     |
+//! +--------+--------+--------+-----+
+//! ```
+//!
+//! A secret slot seals the data key in a `secretbox` keyed from a secret, a
+//! keyfile or both, and records how that key was derived. The slot type says
+//! which of the factors it needs:
+//!
+//! ```text
+//! +-----+----------+----------+----------+----------+----------+
+//! | KDF | OPSLIMIT | MEMLIMIT |   SALT   |  NONCE   | WRAPPED  |
+//! +-----+----------+----------+----------+----------+----------+
+//! | u8  |  u64 BE  |  u64 BE  | [u8; 32] | [u8; 24] | [u8; 48] |
+//! +-----+----------+----------+----------+----------+----------+
+//! ```
+//!
+//! A recipient slot is the data key sealed with `crypto_box_seal`.
+
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::crypto::secretstream;
+
+use super::{
+    errors,
+    recipient::{Identity, PublicKey, WRAPPED_KEY_BYTES},
+    secret::{Factors, Kdf, KdfAlgorithm, Nonce, TarboxSecret, NONCEBYTES, SALTBYTES},
+};
+
+pub const SLOT_SECRET: u8 = 0x1;
+pub const SLOT_RECIPIENT: u8 = 0x2;
+pub const SLOT_SECRET_KEYFILE: u8 = 0x3;
+pub const SLOT_KEYFILE: u8 = 0x4;
+
+/// Most key slots a tarbox may hold.
+pub const MAX_KEY_SLOTS: usize = 32;
+
+/// Most work, in thousandths of a derivation at the sensitive preset, that
+/// the KDFs of all secret slots may add up to. The header is only
+/// authenticated once a slot opens, so a crafted one could otherwise make
+/// `unseal` try a long list of costly slots.
+pub const MAX_KDF_COST: u64 = 4000;
+
+/// Size of the data key sealed in a `secretbox`.
+const SEALED_KEY_BYTES: usize = secretstream::KEYBYTES + secretbox::MACBYTES;
+
+/// Size of the body of a secret slot.
+const SECRET_SLOT_BYTES: usize = 1 + 8 + 8 + SALTBYTES + NONCEBYTES + SEALED_KEY_BYTES;
+
+/// Key the payload of a tarbox with key slots is encrypted with.
+pub type DataKey = secretstream::Key;
+
+/// Generates a random data key.
+pub fn gen_data_key() -> DataKey {
+    secretstream::gen_key()
+}
+
+/// One wrapped copy of the data key.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySlot {
+    /// The data key sealed under a key derived from a secret, a keyfile or
+    /// both, as `factors` says.
+    Secret {
+        factors: Factors,
+        kdf: Kdf,
+        salt: [u8; SALTBYTES],
+        nonce: [u8; NONCEBYTES],
+        wrapped: Vec<u8>,
+    },
+    /// The data key sealed for the public key of a recipient.
+    Recipient { wrapped: Vec<u8> },
+}
+
+impl KeySlot {
+    /// Wraps `key` under a key derived from `secret` and its keyfile.
+    pub fn secret(secret: &TarboxSecret, key: &DataKey) -> errors::Result<KeySlot> {
+        let nonce = secretbox::gen_nonce();
+        let wrapped = secretbox::seal(&key.0, &nonce, &secret.key()?);
+
+        Ok(KeySlot::Secret {
+            factors: secret.factors(),
+            kdf: *secret.kdf(),
+            salt: secret.salt().0,
+            nonce: nonce.0,
+            wrapped: wrapped,
+        })
+    }
+
+    /// Wraps `key` for `recipient`.
+    pub fn recipient(recipient: &PublicKey, key: &DataKey) -> KeySlot {
+        KeySlot::Recipient {
+            wrapped: recipient.wrap(key),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KeySlot::Secret { factors, .. } => factors.name(),
+            KeySlot::Recipient { .. } => "recipient",
+        }
+    }
+
+    /// What opens a secret slot, or `None` for a recipient slot.
+    pub fn factors(&self) -> Option<Factors> {
+        match *self {
+            KeySlot::Secret { factors, .. } => Some(factors),
+            KeySlot::Recipient { .. } => None,
+        }
+    }
+
+    /// Unwraps the data key with `secret`, which must have been built with
+    /// the salt, KDF and factors of this slot. Returns `None` if the secret
+    /// is wrong or this is not a secret slot.
+    pub fn open_with_secret(&self, secret: &TarboxSecret) -> errors::Result<Option<DataKey>> {
+        match *self {
+            KeySlot::Secret {
+                ref nonce,
+                ref wrapped,
+                ..
+            } => {
+                let opened = secretbox::open(wrapped, &Nonce(*nonce), &secret.key()?);
+                Ok(opened.ok().and_then(|key| DataKey::from_slice(&key)))
+            }
+            KeySlot::Recipient { .. } => Ok(None),
+        }
+    }
+
+    /// Unwraps the data key with `identity`. Returns `None` if the slot was
+    /// made for someone else or is not a recipient slot.
+    pub fn open_with_identity(&self, identity: &Identity) -> Option<DataKey> {
+        match *self {
+            KeySlot::Recipient { ref wrapped } => identity.open(wrapped),
+            KeySlot::Secret { .. } => None,
+        }
+    }
+
+    fn body(&self) -> Vec<u8> {
+        match *self {
+            KeySlot::Secret {
+                ref kdf,
+                ref salt,
+                ref nonce,
+                ref wrapped,
+                ..
+            } => {
+                let mut body = Vec::with_capacity(SECRET_SLOT_BYTES);
+                body.push(kdf.algorithm().id());
+                body.extend_from_slice(&kdf.opslimit().to_be_bytes());
+                body.extend_from_slice(&kdf.memlimit().to_be_bytes());
+                body.extend_from_slice(salt);
+                body.extend_from_slice(nonce);
+                body.extend_from_slice(wrapped);
+                body
+            }
+            KeySlot::Recipient { ref wrapped } => wrapped.clone(),
+        }
+    }
+
+    fn from_body(slot_type: u8, body: &[u8]) -> Option<KeySlot> {
+        let factors = match slot_type {
+            SLOT_SECRET => Factors::Secret,
+            SLOT_SECRET_KEYFILE => Factors::SecretAndKeyfile,
+            SLOT_KEYFILE => Factors::Keyfile,
+            SLOT_RECIPIENT if body.len() == WRAPPED_KEY_BYTES => {
+                return Some(KeySlot::Recipient {
+                    wrapped: body.to_vec(),
+                })
+            }
+            _ => return None,
+        };
+
+        if body.len() != SECRET_SLOT_BYTES {
+            return None;
+        }
+
+        let algorithm = KdfAlgorithm::from_id(body[0])?;
+        let mut ops = [0; 8];
+        let mut mem = [0; 8];
+        ops.copy_from_slice(&body[1..9]);
+        mem.copy_from_slice(&body[9..17]);
+        let kdf = Kdf::new(algorithm, u64::from_be_bytes(ops), u64::from_be_bytes(mem));
+        if !kdf.within_limits() {
+            return None;
+        }
+
+        let body = &body[17..];
+        let mut salt = [0; SALTBYTES];
+        let mut nonce = [0; NONCEBYTES];
+        salt.copy_from_slice(&body[..SALTBYTES]);
+        nonce.copy_from_slice(&body[SALTBYTES..SALTBYTES + NONCEBYTES]);
+
+        Some(KeySlot::Secret {
+            factors: factors,
+            kdf: kdf,
+            salt: salt,
+            nonce: nonce,
+            wrapped: body[SALTBYTES + NONCEBYTES..].to_vec(),
+        })
+    }
+
+    fn slot_type(&self) -> u8 {
+        match *self {
+            KeySlot::Secret {
+                factors: Factors::Secret,
+                ..
+            } => SLOT_SECRET,
+            KeySlot::Secret {
+                factors: Factors::SecretAndKeyfile,
+                ..
+            } => SLOT_SECRET_KEYFILE,
+            KeySlot::Secret {
+                factors: Factors::Keyfile,
+                ..
+            } => SLOT_KEYFILE,
+            KeySlot::Recipient { .. } => SLOT_RECIPIENT,
+        }
+    }
+}
+
+/// Encodes `slots` as the value of the key slots attribute.
+pub fn to_bytes(slots: &[KeySlot]) -> Vec<u8> {
+    let mut b = Vec::new();
+    for slot in slots {
+        let body = slot.body();
+        b.push(slot.slot_type());
+        b.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        b.extend(body);
+    }
+
+    b
+}
+
+/// Whether `slots` are few and cheap enough to be tried in turn, within
+/// `MAX_KEY_SLOTS` and `MAX_KDF_COST`.
+pub fn within_limits(slots: &[KeySlot]) -> bool {
+    let cost: u64 = slots
+        .iter()
+        .map(|slot| match *slot {
+            KeySlot::Secret { ref kdf, .. } => kdf.cost(),
+            KeySlot::Recipient { .. } => 0,
+        }).sum();
+
+    slots.len() <= MAX_KEY_SLOTS && cost <= MAX_KDF_COST
+}
+
+/// Decodes the value of the key slots attribute. Returns `None` if it is
+/// malformed, empty, or holds a slot type this version does not know.
+pub fn from_bytes(source: &[u8]) -> Option<Vec<KeySlot>> {
+    let mut slots = Vec::new();
+    let mut rest = source;
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            return None;
+        }
+
+        let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        if rest.len() < 3 + len {
+            return None;
+        }
+
+        slots.push(KeySlot::from_body(rest[0], &rest[3..3 + len])?);
+        rest = &rest[3 + len..];
+    }
+
+    if slots.is_empty() {
+        return None;
+    }
+
+    Some(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tarbox::secret::TarboxSecretBuilder;
+
+    #[test]
+    fn test_secret_slot() {
+        let key = gen_data_key();
+        let secret = TarboxSecret::generate(String::from("A-B-C"));
+        let slot = KeySlot::secret(&secret, &key).unwrap();
+        assert_eq!(Some(key), slot.open_with_secret(&secret).unwrap());
+
+        let wrong = TarboxSecretBuilder::new()
+            .password(String::from("A-B-D"))
+            .salt(secret.salt().clone())
+            .build()
+            .unwrap();
+        assert_eq!(None, slot.open_with_secret(&wrong).unwrap());
+        assert_eq!(None, slot.open_with_identity(&Identity::generate()));
+    }
+
+    #[test]
+    fn test_slots_roundtrip() {
+        let key = gen_data_key();
+        let identity = Identity::generate();
+        let slots = vec![
+            KeySlot::secret(&TarboxSecret::generate(String::from("A-B-C")), &key).unwrap(),
+            KeySlot::recipient(&identity.public_key(), &key),
+            KeySlot::Secret {
+                factors: Factors::Keyfile,
+                kdf: Kdf::legacy(),
+                salt: [0x1; SALTBYTES],
+                nonce: [0x2; NONCEBYTES],
+                wrapped: vec![0x3; SEALED_KEY_BYTES],
+            },
+        ];
+
+        let encoded = to_bytes(&slots);
+        assert_eq!(2 * (3 + SECRET_SLOT_BYTES) + 3 + WRAPPED_KEY_BYTES, encoded.len());
+        assert_eq!(SLOT_KEYFILE, encoded[2 * 3 + SECRET_SLOT_BYTES + WRAPPED_KEY_BYTES]);
+
+        let decoded = from_bytes(&encoded).unwrap();
+        assert_eq!(slots, decoded);
+        assert_eq!(Some(key), decoded[1].open_with_identity(&identity));
+
+        assert_eq!(None, from_bytes(&[]));
+        assert_eq!(None, from_bytes(&encoded[..encoded.len() - 1]));
+        assert_eq!(None, from_bytes(&[0x7f, 0x0, 0x1, 0x0]));
+    }
+}
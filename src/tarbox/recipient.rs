@@ -0,0 +1,178 @@
+//! Public-key recipients.
+//!
+//! The data key of a tarbox can be wrapped for a recipient with
+//! `crypto_box_seal` and stored in a key slot, so that the recipient's
+//! identity opens the tarbox without a shared secret.
+//!
+//! Public keys are written as base64. An identity file holds the base64
+//! secret key on its first non-comment line:
+//!
+//! ```text
+//! # sneakercopy identity
+//! # public key: cXhq...
+//! 3yYa...
+//! ```
+
+use base64;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::sealedbox;
+use sodiumoxide::crypto::secretstream;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::{errors, keyslot::DataKey};
+
+/// Size of a data key wrapped for one recipient.
+pub const WRAPPED_KEY_BYTES: usize = secretstream::KEYBYTES + sealedbox::SEALBYTES;
+
+/// X25519 public key of a recipient.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PublicKey(box_::PublicKey);
+
+impl PublicKey {
+    /// Wraps `key` so that only the holder of the matching identity can
+    /// unwrap it.
+    pub fn wrap(&self, key: &DataKey) -> Vec<u8> {
+        sealedbox::seal(&key.0, &self.0)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", base64::encode(&(self.0).0))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = errors::Error;
+
+    fn from_str(s: &str) -> errors::Result<Self> {
+        let bytes = base64::decode(s.trim())
+            .map_err(|_| errors::ErrorKind::InvalidPublicKey(s.to_string()))?;
+
+        match box_::PublicKey::from_slice(&bytes) {
+            Some(pk) => Ok(PublicKey(pk)),
+            None => bail!(errors::ErrorKind::InvalidPublicKey(s.to_string())),
+        }
+    }
+}
+
+/// The secret half of a recipient key pair.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    public: box_::PublicKey,
+    secret: box_::SecretKey,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        let (public, secret) = box_::gen_keypair();
+        Identity {
+            public: public,
+            secret: secret,
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.public)
+    }
+
+    /// Reads the identity file at `path`.
+    pub fn read_from(path: &Path) -> errors::Result<Identity> {
+        let reader = BufReader::new(File::open(path)?);
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let bytes =
+                base64::decode(line).map_err(|_| errors::ErrorKind::InvalidIdentity(display(path)))?;
+            let secret = match box_::SecretKey::from_slice(&bytes) {
+                Some(secret) => secret,
+                None => bail!(errors::ErrorKind::InvalidIdentity(display(path))),
+            };
+
+            return Ok(Identity {
+                public: secret.public_key(),
+                secret: secret,
+            });
+        }
+
+        bail!(errors::ErrorKind::InvalidIdentity(display(path)))
+    }
+
+    /// Writes the identity to a new file at `path`, readable only by its
+    /// owner. An existing file is never replaced.
+    pub fn write_to(&self, path: &Path) -> errors::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+
+        writeln!(file, "# sneakercopy identity")?;
+        writeln!(file, "# public key: {}", self.public_key())?;
+        writeln!(file, "{}", base64::encode(&self.secret.0))?;
+
+        Ok(())
+    }
+
+    /// Unwraps a data key, or returns `None` if `wrapped` was not made for
+    /// this identity.
+    pub fn open(&self, wrapped: &[u8]) -> Option<DataKey> {
+        sealedbox::open(wrapped, &self.public, &self.secret)
+            .ok()
+            .and_then(|key| DataKey::from_slice(&key))
+    }
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use tarbox::keyslot::gen_data_key;
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let key = gen_data_key();
+
+        let wrapped = alice.public_key().wrap(&key);
+        assert_eq!(WRAPPED_KEY_BYTES, wrapped.len());
+        assert_eq!(Some(key.clone()), alice.open(&wrapped));
+        assert_eq!(None, bob.open(&wrapped));
+    }
+
+    #[test]
+    fn test_identity_file() {
+        let path = env::temp_dir().join(format!("sneakercopy-{}-identity", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let identity = Identity::generate();
+        identity.write_to(&path).unwrap();
+        assert!(identity.write_to(&path).is_err());
+
+        let read = Identity::read_from(&path).unwrap();
+        assert_eq!(identity.public_key(), read.public_key());
+
+        let public = identity.public_key().to_string();
+        assert_eq!(identity.public_key(), public.parse().unwrap());
+        assert!("not a key".parse::<PublicKey>().is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
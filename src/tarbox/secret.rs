@@ -1,10 +1,19 @@
 use base64;
+use sodiumoxide::crypto::generichash;
 use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::pwhash::{argon2id13, scryptsalsa208sha256};
 pub use sodiumoxide::crypto::pwhash::scryptsalsa208sha256::{
-    Salt, MEMLIMIT_INTERACTIVE, OPSLIMIT_INTERACTIVE, SALTBYTES,
+    gen_salt, Salt, MEMLIMIT_INTERACTIVE, OPSLIMIT_INTERACTIVE, SALTBYTES,
 };
 use sodiumoxide::crypto::secretbox;
 pub use sodiumoxide::crypto::secretbox::xsalsa20poly1305::{Key, Nonce, KEYBYTES, NONCEBYTES};
+use sodiumoxide::crypto::secretstream;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::errors;
 
 pub fn decode_nonce(nonce: String) -> Option<Nonce> {
     let bytes = base64::decode(&nonce).unwrap();
@@ -16,57 +25,438 @@ pub fn decode_salt(salt: String) -> Option<Salt> {
     Salt::from_slice(bytes.as_slice())
 }
 
+/// Size of the hash a keyfile is reduced to.
+pub const KEYFILE_HASH_BYTES: usize = 32;
+
+/// The BLAKE2b hash of a keyfile's contents, which keys the hash of the
+/// password that goes into the KDF.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyfile([u8; KEYFILE_HASH_BYTES]);
+
+impl Keyfile {
+    /// Hashes the keyfile at `path`. Empty files are refused.
+    pub fn read_from(path: &Path) -> errors::Result<Keyfile> {
+        let mut file = File::open(path)?;
+        let mut state = generichash::State::new(Some(KEYFILE_HASH_BYTES), None).unwrap();
+
+        let mut buf = vec![0; 64 * 1024];
+        let mut total = 0;
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            state.update(&buf[..read]).unwrap();
+            total += read;
+        }
+
+        if total == 0 {
+            bail!(errors::ErrorKind::EmptyKeyfile(
+                path.to_string_lossy().into_owned()
+            ));
+        }
+
+        let mut hash = [0; KEYFILE_HASH_BYTES];
+        hash.copy_from_slice(&state.finalize().unwrap()[..]);
+        Ok(Keyfile(hash))
+    }
+}
+
+/// What a key is derived from, and so what has to be supplied to open a
+/// key slot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Factors {
+    Secret,
+    SecretAndKeyfile,
+    Keyfile,
+}
+
+impl Factors {
+    pub fn needs_secret(&self) -> bool {
+        *self != Factors::Keyfile
+    }
+
+    pub fn needs_keyfile(&self) -> bool {
+        *self != Factors::Secret
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Factors::Secret => "secret",
+            Factors::SecretAndKeyfile => "secret+keyfile",
+            Factors::Keyfile => "keyfile",
+        }
+    }
+}
+
+/// Password hashing function used to derive a tarbox key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KdfAlgorithm {
+    ScryptSalsa208Sha256,
+    Argon2id13,
+}
+
+impl KdfAlgorithm {
+    pub fn id(&self) -> u8 {
+        match *self {
+            KdfAlgorithm::ScryptSalsa208Sha256 => 0x1,
+            KdfAlgorithm::Argon2id13 => 0x2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<KdfAlgorithm> {
+        match id {
+            0x1 => Some(KdfAlgorithm::ScryptSalsa208Sha256),
+            0x2 => Some(KdfAlgorithm::Argon2id13),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KdfAlgorithm::ScryptSalsa208Sha256 => "scrypt",
+            KdfAlgorithm::Argon2id13 => "argon2id",
+        }
+    }
+}
+
+impl FromStr for KdfAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scrypt" => Ok(KdfAlgorithm::ScryptSalsa208Sha256),
+            "argon2id" => Ok(KdfAlgorithm::Argon2id13),
+            _ => Err(format!("unknown kdf: {}", s)),
+        }
+    }
+}
+
+/// Cost presets offered by libsodium's password hashing functions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KdfCost {
+    Interactive,
+    Moderate,
+    Sensitive,
+}
+
+impl KdfCost {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KdfCost::Interactive => "interactive",
+            KdfCost::Moderate => "moderate",
+            KdfCost::Sensitive => "sensitive",
+        }
+    }
+}
+
+impl FromStr for KdfCost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interactive" => Ok(KdfCost::Interactive),
+            "moderate" => Ok(KdfCost::Moderate),
+            "sensitive" => Ok(KdfCost::Sensitive),
+            _ => Err(format!("unknown kdf cost: {}", s)),
+        }
+    }
+}
+
+/// A key derivation function along with the cost parameters it runs with.
+/// Both are recorded in the tarbox header so the cost can be raised for new
+/// tarboxes without making old ones unreadable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Kdf {
+    algorithm: KdfAlgorithm,
+    opslimit: u64,
+    memlimit: u64,
+}
+
+impl Kdf {
+    pub fn new(algorithm: KdfAlgorithm, opslimit: u64, memlimit: u64) -> Kdf {
+        Kdf {
+            algorithm: algorithm,
+            opslimit: opslimit,
+            memlimit: memlimit,
+        }
+    }
+
+    /// The KDF used by tarboxes that do not record one: scrypt at
+    /// interactive cost.
+    pub fn legacy() -> Kdf {
+        Kdf::new(
+            KdfAlgorithm::ScryptSalsa208Sha256,
+            OPSLIMIT_INTERACTIVE.0 as u64,
+            MEMLIMIT_INTERACTIVE.0 as u64,
+        )
+    }
+
+    /// Returns `algorithm` with libsodium's cost parameters for `cost`.
+    /// libsodium has no moderate preset for scrypt.
+    pub fn preset(algorithm: KdfAlgorithm, cost: KdfCost) -> errors::Result<Kdf> {
+        let (opslimit, memlimit) = match (algorithm, cost) {
+            (KdfAlgorithm::ScryptSalsa208Sha256, KdfCost::Interactive) => (
+                scryptsalsa208sha256::OPSLIMIT_INTERACTIVE.0,
+                scryptsalsa208sha256::MEMLIMIT_INTERACTIVE.0,
+            ),
+            (KdfAlgorithm::ScryptSalsa208Sha256, KdfCost::Sensitive) => (
+                scryptsalsa208sha256::OPSLIMIT_SENSITIVE.0,
+                scryptsalsa208sha256::MEMLIMIT_SENSITIVE.0,
+            ),
+            (KdfAlgorithm::Argon2id13, KdfCost::Interactive) => (
+                argon2id13::OPSLIMIT_INTERACTIVE.0,
+                argon2id13::MEMLIMIT_INTERACTIVE.0,
+            ),
+            (KdfAlgorithm::Argon2id13, KdfCost::Moderate) => (
+                argon2id13::OPSLIMIT_MODERATE.0,
+                argon2id13::MEMLIMIT_MODERATE.0,
+            ),
+            (KdfAlgorithm::Argon2id13, KdfCost::Sensitive) => (
+                argon2id13::OPSLIMIT_SENSITIVE.0,
+                argon2id13::MEMLIMIT_SENSITIVE.0,
+            ),
+            (algorithm, cost) => bail!(errors::ErrorKind::KdfPresetUnavailable(
+                algorithm.name().to_string(),
+                cost.name().to_string()
+            )),
+        };
+
+        Ok(Kdf::new(algorithm, opslimit as u64, memlimit as u64))
+    }
+
+    pub fn algorithm(&self) -> KdfAlgorithm {
+        self.algorithm
+    }
+
+    pub fn opslimit(&self) -> u64 {
+        self.opslimit
+    }
+
+    pub fn memlimit(&self) -> u64 {
+        self.memlimit
+    }
+
+    /// Whether the cost parameters are no higher than the algorithm's
+    /// sensitive preset. Parameters read from a header are checked against
+    /// this so a crafted tarbox cannot demand unbounded time or memory.
+    pub fn within_limits(&self) -> bool {
+        let (max_ops, max_mem) = match self.algorithm {
+            KdfAlgorithm::ScryptSalsa208Sha256 => (
+                scryptsalsa208sha256::OPSLIMIT_SENSITIVE.0,
+                scryptsalsa208sha256::MEMLIMIT_SENSITIVE.0,
+            ),
+            KdfAlgorithm::Argon2id13 => (
+                argon2id13::OPSLIMIT_SENSITIVE.0,
+                argon2id13::MEMLIMIT_SENSITIVE.0,
+            ),
+        };
+
+        self.opslimit <= max_ops as u64 && self.memlimit <= max_mem as u64
+    }
+
+    /// The work a derivation takes, in thousandths of one at the
+    /// algorithm's sensitive preset, rounded up.
+    pub fn cost(&self) -> u64 {
+        let max = Kdf::preset(self.algorithm, KdfCost::Sensitive).unwrap();
+        let work = self.opslimit as u128 * self.memlimit as u128 * 1000;
+        let max_work = max.opslimit as u128 * max.memlimit as u128;
+        ((work + max_work - 1) / max_work) as u64
+    }
+
+    fn derive_key(&self, buffer: &mut [u8], password: &[u8], salt: &Salt) -> errors::Result<()> {
+        let derived = match self.algorithm {
+            KdfAlgorithm::ScryptSalsa208Sha256 => scryptsalsa208sha256::derive_key(
+                buffer,
+                password,
+                salt,
+                scryptsalsa208sha256::OpsLimit(self.opslimit as usize),
+                scryptsalsa208sha256::MemLimit(self.memlimit as usize),
+            ).map(|_| ()),
+            KdfAlgorithm::Argon2id13 => {
+                // Argon2 takes a shorter salt than scrypt; the leading bytes
+                // of the (uniformly random) tarbox salt are used.
+                let salt = argon2id13::Salt::from_slice(&salt.0[..argon2id13::SALTBYTES]).unwrap();
+                argon2id13::derive_key(
+                    buffer,
+                    password,
+                    &salt,
+                    argon2id13::OpsLimit(self.opslimit as usize),
+                    argon2id13::MemLimit(self.memlimit as usize),
+                ).map(|_| ())
+            }
+        };
+
+        match derived {
+            Ok(()) => Ok(()),
+            Err(_) => bail!(errors::ErrorKind::KeyDerivationFail),
+        }
+    }
+}
+
 builder!(pub : TarboxSecretBuilder => TarboxSecret {
     password: String = None,
-    nonce: Nonce = None,
-    salt: Salt = None
+    salt: Salt = None,
+    kdf: Kdf = Some(Kdf::legacy()),
+    keyfile: Option<Keyfile> = Some(None)
 });
 
 impl TarboxSecret {
     /// Make a brand new _random_ `TarboxSecret` to use for encrypting a tarbox.
     pub fn generate(password: String) -> TarboxSecret {
+        TarboxSecret::generate_with_kdf(password, Kdf::legacy())
+    }
+
+    /// Like `generate`, but derives the key with the given `kdf`.
+    pub fn generate_with_kdf(password: String, kdf: Kdf) -> TarboxSecret {
         TarboxSecret {
             password: password.clone(),
-            nonce: secretbox::gen_nonce(),
             salt: pwhash::gen_salt(),
+            kdf: kdf,
+            keyfile: None,
         }
     }
 
-    pub fn key(&self) -> Key {
-        // derive the actual key from the password and salt
+    /// Requires `keyfile` along with the password. With an empty password,
+    /// the keyfile alone derives the key.
+    pub fn set_keyfile(&mut self, keyfile: Keyfile) {
+        self.keyfile = Some(keyfile);
+    }
+
+    /// Derives the `secretbox` key used by version 1 tarboxes.
+    pub fn key(&self) -> errors::Result<Key> {
         let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
 
         {
             let secretbox::Key(ref mut buffer) = key;
-            pwhash::derive_key(
-                buffer,
-                self.password.as_bytes(),
-                &self.salt,
-                OPSLIMIT_INTERACTIVE,
-                MEMLIMIT_INTERACTIVE,
-            ).unwrap();
+            self.derive_key(buffer)?;
         }
 
-        key
+        Ok(key)
     }
 
-    pub fn password(&self) -> &String {
-        &self.password
+    /// Derives the `secretstream` key used by chunked tarboxes.
+    pub fn stream_key(&self) -> errors::Result<secretstream::Key> {
+        let mut key = secretstream::Key([0; secretstream::KEYBYTES]);
+
+        {
+            let secretstream::Key(ref mut buffer) = key;
+            self.derive_key(buffer)?;
+        }
+
+        Ok(key)
     }
 
-    pub fn nonce(&self) -> &Nonce {
-        &self.nonce
+    fn derive_key(&self, buffer: &mut [u8]) -> errors::Result<()> {
+        // With a keyfile, the KDF input is the password hashed with BLAKE2b
+        // keyed by the keyfile hash. Unlike a concatenation of the two, no
+        // other password and keyfile pair maps to the same input.
+        let input = match self.keyfile {
+            Some(Keyfile(ref hash)) => {
+                let mut state =
+                    generichash::State::new(Some(KEYFILE_HASH_BYTES), Some(hash)).unwrap();
+                state.update(self.password.as_bytes()).unwrap();
+                state.finalize().unwrap()[..].to_vec()
+            }
+            None => self.password.as_bytes().to_vec(),
+        };
+
+        // derive the actual key from the password and salt
+        self.kdf.derive_key(buffer, &input, &self.salt)
     }
 
-    pub fn encoded_nonce(&self) -> String {
-        String::from(base64::encode(&self.nonce.0))
+    pub fn password(&self) -> &String {
+        &self.password
     }
 
     pub fn salt(&self) -> &Salt {
         &self.salt
     }
 
+    pub fn kdf(&self) -> &Kdf {
+        &self.kdf
+    }
+
+    pub fn factors(&self) -> Factors {
+        match (self.password.is_empty(), self.keyfile.is_some()) {
+            (false, true) => Factors::SecretAndKeyfile,
+            (true, true) => Factors::Keyfile,
+            (_, false) => Factors::Secret,
+        }
+    }
+
     pub fn encoded_salt(&self) -> String {
         String::from(base64::encode(&self.salt.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_presets() {
+        let kdf = Kdf::preset(KdfAlgorithm::Argon2id13, KdfCost::Sensitive).unwrap();
+        assert_eq!(kdf.algorithm(), KdfAlgorithm::Argon2id13);
+        assert!(kdf.within_limits());
+
+        assert_eq!(
+            Kdf::preset(KdfAlgorithm::ScryptSalsa208Sha256, KdfCost::Interactive).unwrap(),
+            Kdf::legacy()
+        );
+        assert!(Kdf::preset(KdfAlgorithm::ScryptSalsa208Sha256, KdfCost::Moderate).is_err());
+    }
+
+    #[test]
+    fn test_argon2id_key() {
+        let kdf = Kdf::preset(KdfAlgorithm::Argon2id13, KdfCost::Interactive).unwrap();
+        let secret = TarboxSecret::generate_with_kdf(String::from("A-B-C"), kdf);
+        let scrypt = TarboxSecretBuilder::new()
+            .password(String::from("A-B-C"))
+            .salt(secret.salt().clone())
+            .build()
+            .unwrap();
+
+        let key = secret.stream_key().unwrap();
+        assert_eq!(key, secret.stream_key().unwrap());
+        assert_ne!(key, scrypt.stream_key().unwrap());
+    }
+
+    #[test]
+    fn test_keyfile_factors() {
+        let path = env::temp_dir().join(format!("sneakercopy-{}-keyfile", process::id()));
+        fs::write(&path, b"").unwrap();
+        assert!(Keyfile::read_from(&path).is_err());
+        fs::write(&path, b"keyfile contents").unwrap();
+        let keyfile = Keyfile::read_from(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let secret = TarboxSecret::generate(String::from("A-B-C"));
+        let build = |password: &str, keyfile: Option<Keyfile>| {
+            TarboxSecretBuilder::new()
+                .password(String::from(password))
+                .salt(secret.salt().clone())
+                .keyfile(keyfile)
+                .build()
+                .unwrap()
+        };
+
+        let both = build("A-B-C", Some(keyfile));
+        let only = build("", Some(keyfile));
+        assert_eq!(Factors::Secret, secret.factors());
+        assert_eq!(Factors::SecretAndKeyfile, both.factors());
+        assert_eq!(Factors::Keyfile, only.factors());
+
+        let key = both.stream_key().unwrap();
+        assert_ne!(key, secret.stream_key().unwrap());
+        assert_ne!(key, only.stream_key().unwrap());
+
+        // A keyfile must not stand in for the tail of a password
+        let spelled = build(&"A".repeat(KEYFILE_HASH_BYTES), None);
+        let hashed = build("", Some(Keyfile([b'A'; KEYFILE_HASH_BYTES])));
+        assert_ne!(spelled.stream_key().unwrap(), hashed.stream_key().unwrap());
+    }
+}